@@ -0,0 +1,605 @@
+use chrono::{DateTime, Datelike, Utc};
+use once_cell::unsync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::pool::ConnectionPool;
+use crate::schema;
+use crate::settings::SETTINGS;
+use crate::{
+    apply_title_case, attachment_filename, clean_source_url, clean_title, collection_path,
+    detect_zotero_schema_version, filter_tags, format_author_list, get_attachment_path, parse_date,
+    parse_page_count, query_attachment_annotations_count, query_child_notes_for_paper,
+    query_highlight_counts, query_highlights, query_highlights_for_paper,
+    query_items_modified_since, query_papers, query_related_items, split_creators, zotero_item_url,
+    Annotation, Author, HighlightJson, NoteContent, Paper, PDF_CONTENT_TYPE,
+};
+
+/// Source of the papers and highlights of the Zotero library.
+pub trait ZoteroBackend {
+    /// Papers that have at least one attachment.
+    fn papers(&self) -> Result<Vec<Paper>, Box<dyn Error>>;
+
+    /// Papers that were modified after `since`, or whose attachments or annotations were.
+    fn papers_modified_since(&self, since: DateTime<Utc>) -> Result<Vec<Paper>, Box<dyn Error>>;
+
+    /// Highlights by paper id, in reading order.
+    fn highlights(&self) -> Result<HashMap<String, Vec<HighlightJson>>, Box<dyn Error>>;
+
+    /// Highlights of a single paper, in reading order.
+    fn paper_highlights(&self, paper_id: &str) -> Result<Vec<HighlightJson>, Box<dyn Error>> {
+        Ok(self.highlights()?.remove(paper_id).unwrap_or_default())
+    }
+
+    /// Number of highlights by paper id, for papers that have any.
+    fn highlight_counts(&self) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+        Ok(self
+            .highlights()?
+            .into_iter()
+            .map(|(paper_id, highlights)| (paper_id, highlights.len()))
+            .collect())
+    }
+
+    /// Number of highlights of a single paper.
+    fn paper_highlight_count(&self, paper_id: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(self.paper_highlights(paper_id)?.len())
+    }
+
+    /// IDs of the items related to each of `paper_ids`.
+    fn related_items(
+        &self,
+        paper_ids: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>>;
+
+    /// Child notes of a paper, in the order they were added.
+    fn child_notes(&self, paper_id: &str) -> Result<Vec<NoteContent>, Box<dyn Error>>;
+}
+
+/// Reads a copy of the Zotero SQLite database.
+pub struct SqliteBackend {
+    pool: ConnectionPool,
+    schema_version: u32,
+}
+
+impl SqliteBackend {
+    pub fn new(pool: ConnectionPool) -> rusqlite::Result<Self> {
+        let schema_version = detect_zotero_schema_version(&*pool.get()?)?;
+        // Zotero 7 (userdata schema 120, the `-- <version>` line at the top of
+        // resource/schema/userdata.sql) kept the itemAnnotations layout of
+        // Zotero 6, so the same queries read both: dispatch on the version here
+        // once a schema change needs it.
+        tracing::info!("Detected Zotero schema version {}", schema_version);
+        Ok(SqliteBackend {
+            pool,
+            schema_version,
+        })
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Expected tables and columns missing from the database, see `schema`.
+    pub fn missing_schema_items(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(schema::missing_schema_items(&*self.pool.get()?)?)
+    }
+}
+
+impl ZoteroBackend for SqliteBackend {
+    fn papers(&self) -> Result<Vec<Paper>, Box<dyn Error>> {
+        Ok(query_papers(&*self.pool.get()?, None)?)
+    }
+
+    fn papers_modified_since(&self, since: DateTime<Utc>) -> Result<Vec<Paper>, Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        let item_ids = query_items_modified_since(&conn, since)?;
+        Ok(query_papers(&conn, Some(&item_ids))?)
+    }
+
+    fn highlights(&self) -> Result<HashMap<String, Vec<HighlightJson>>, Box<dyn Error>> {
+        Ok(query_highlights(&*self.pool.get()?, None)?)
+    }
+
+    fn paper_highlights(&self, paper_id: &str) -> Result<Vec<HighlightJson>, Box<dyn Error>> {
+        Ok(query_highlights_for_paper(
+            &*self.pool.get()?,
+            paper_id.parse()?,
+        )?)
+    }
+
+    fn highlight_counts(&self) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+        Ok(query_highlight_counts(&*self.pool.get()?)?)
+    }
+
+    fn paper_highlight_count(&self, paper_id: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(query_attachment_annotations_count(
+            &*self.pool.get()?,
+            paper_id.parse()?,
+        )?)
+    }
+
+    fn related_items(
+        &self,
+        paper_ids: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
+        Ok(query_related_items(&*self.pool.get()?, paper_ids)?)
+    }
+
+    fn child_notes(&self, paper_id: &str) -> Result<Vec<NoteContent>, Box<dyn Error>> {
+        Ok(query_child_notes_for_paper(
+            &*self.pool.get()?,
+            paper_id.parse()?,
+        )?)
+    }
+}
+
+/// Largest page size accepted by the Zotero API.
+const API_PAGE_SIZE: usize = 100;
+
+/// Reads the user library from the local HTTP API of a running Zotero 7.
+///
+/// The API identifies items by key only, so papers get `@zotero_<key>` refs
+/// instead of the `@zotero_<itemID>` refs used with the database, and
+/// `skip_item_ids` doesn't apply.
+pub struct LocalApiBackend {
+    base_url: String,
+    client: reqwest::blocking::Client,
+    // Both papers and highlights are built from one listing of all items
+    items: OnceCell<Vec<ApiItem>>,
+    // Built once for `paper_highlights`, as all items are in memory anyway
+    highlights_by_paper: OnceCell<HashMap<String, Vec<HighlightJson>>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiItem {
+    key: String,
+    data: ApiItemData,
+    #[serde(default)]
+    meta: ApiItemMeta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ApiItemMeta {
+    // Publication date as YYYY-MM-DD, YYYY-MM or YYYY
+    parsed_date: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ApiItemData {
+    item_type: String,
+    title: Option<String>,
+    short_title: Option<String>,
+    series: Option<String>,
+    series_number: Option<String>,
+    pages: Option<String>,
+    num_pages: Option<String>,
+    volume: Option<String>,
+    number_of_volumes: Option<String>,
+    url: Option<String>,
+    date_added: String,
+    date_modified: String,
+    access_date: Option<String>,
+    creators: Vec<ApiCreator>,
+    parent_item: Option<String>,
+    content_type: Option<String>,
+    filename: Option<String>,
+    path: Option<String>,
+    link_mode: Option<String>,
+    annotation_text: Option<String>,
+    annotation_comment: Option<String>,
+    annotation_sort_index: Option<String>,
+    annotation_color: Option<String>,
+    // HTML of notes
+    note: Option<String>,
+    // e.g. {"dc:relation": ["http://zotero.org/users/local/abcd1234/items/ABCD2345"]},
+    // with a single URI as a string
+    relations: HashMap<String, serde_json::Value>,
+    // Keys of the collections the item is in
+    collections: Vec<String>,
+    tags: Vec<ApiTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTag {
+    tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiCollection {
+    key: String,
+    data: ApiCollectionData,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiCollectionData {
+    name: String,
+    // Key of the parent collection, or false for top-level collections
+    parent_collection: serde_json::Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ApiCreator {
+    creator_type: String,
+    first_name: String,
+    last_name: String,
+    // Set instead of first_name and last_name for single-field creators
+    name: Option<String>,
+}
+
+/// Whether `item` is an attachment allowed by the `content_type_filter` setting.
+fn is_considered_attachment(item: &ApiItem) -> bool {
+    item.data.item_type == "attachment"
+        && (SETTINGS.content_type_filter.is_empty()
+            || item
+                .data
+                .content_type
+                .as_ref()
+                .is_some_and(|content_type| SETTINGS.content_type_filter.contains(content_type)))
+}
+
+impl LocalApiBackend {
+    pub fn new(port: u16) -> Self {
+        LocalApiBackend {
+            base_url: format!("http://127.0.0.1:{}/api/users/0", port),
+            client: reqwest::blocking::Client::new(),
+            items: OnceCell::new(),
+            highlights_by_paper: OnceCell::new(),
+        }
+    }
+
+    fn items(&self) -> Result<&[ApiItem], Box<dyn Error>> {
+        self.items
+            .get_or_try_init(|| self.fetch_items())
+            .map(Vec::as_slice)
+    }
+
+    fn fetch_items(&self) -> Result<Vec<ApiItem>, Box<dyn Error>> {
+        self.fetch_all("items")
+    }
+
+    /// Fetches all pages of a listing of the library, e.g. `items`.
+    fn fetch_all<T: DeserializeOwned>(&self, listing: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        let url = format!("{}/{}", self.base_url, listing);
+        let mut items: Vec<T> = Vec::new();
+        loop {
+            tracing::debug!("GET {} (start {})", url, items.len());
+            let page: Vec<T> = self
+                .client
+                .get(&url)
+                .query(&[
+                    ("format", "json".to_string()),
+                    ("limit", API_PAGE_SIZE.to_string()),
+                    ("start", items.len().to_string()),
+                ])
+                .send()?
+                .error_for_status()?
+                .json()?;
+            let page_len = page.len();
+            items.extend(page);
+            if page_len < API_PAGE_SIZE {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn api_paper(item: &ApiItem, children: &[&ApiItem]) -> Paper {
+        let data = &item.data;
+        let title = clean_title(data.title.as_deref().unwrap_or_default());
+        let source_url = clean_source_url(data.url.as_deref().unwrap_or_default());
+        let has_url = !source_url.is_empty();
+        let roam_ref = if has_url {
+            source_url.clone()
+        } else {
+            format!("@zotero_{}", item.key)
+        };
+
+        let creators: Vec<(String, Author)> = data
+            .creators
+            .iter()
+            .map(|creator| {
+                let name = match &creator.name {
+                    Some(name) => name.clone(),
+                    None => format!("{} {}", creator.first_name, creator.last_name)
+                        .trim()
+                        .to_string(),
+                };
+                let author = Author {
+                    first_name: creator.first_name.clone(),
+                    last_name: creator.name.clone().unwrap_or(creator.last_name.clone()),
+                    name,
+                    orcid: None,
+                    creator_id: None,
+                };
+                let creator_type = match creator.creator_type.as_str() {
+                    "" => "author",
+                    creator_type => creator_type,
+                };
+                (creator_type.to_string(), author)
+            })
+            .collect();
+        let creators = split_creators(creators, &SETTINGS);
+
+        let mut tags: Vec<String> = data.tags.iter().map(|tag| tag.tag.clone()).collect();
+        tags.sort();
+
+        // The first PDF, or the first other file
+        let attachment = children
+            .iter()
+            .filter(|child| is_considered_attachment(child))
+            .filter(|child| child.data.filename.is_some() || child.data.path.is_some())
+            .min_by_key(|child| child.data.content_type.as_deref() != Some(PDF_CONTENT_TYPE));
+        let attachment_db_path = attachment.and_then(|attachment| {
+            match (&attachment.data.link_mode, &attachment.data.filename) {
+                (Some(link_mode), Some(filename)) if link_mode.starts_with("imported") => {
+                    Some(format!("storage:{}", filename))
+                }
+                _ => attachment.data.path.clone(),
+            }
+        });
+        let attachment_path = attachment
+            .zip(attachment_db_path.as_deref())
+            .and_then(|(attachment, path)| get_attachment_path(path, &attachment.key));
+        let attachment_content_type =
+            attachment.and_then(|attachment| attachment.data.content_type.clone());
+
+        let published_date = item.meta.parsed_date.as_deref().and_then(parse_date);
+
+        Paper {
+            id: item.key.clone(),
+            has_url,
+            roam_ref,
+            source_url,
+            // The local API only serves the personal library
+            zotero_url: zotero_item_url(&item.key, None, &SETTINGS.zotero_uri_scheme),
+            item_key: item.key.clone(),
+            title: apply_title_case(&title, SETTINGS.title_case),
+            title_original: title,
+            short_title: data
+                .short_title
+                .as_deref()
+                .filter(|short_title| !short_title.trim().is_empty())
+                .map(clean_title),
+            series: data.series.clone().filter(|series| !series.is_empty()),
+            series_number: data
+                .series_number
+                .clone()
+                .filter(|number| !number.is_empty()),
+            pages: data.pages.clone().filter(|pages| !pages.is_empty()),
+            page_count: data.num_pages.as_deref().and_then(parse_page_count),
+            volume_number: data.volume.clone().filter(|volume| !volume.is_empty()),
+            number_of_volumes: data
+                .number_of_volumes
+                .clone()
+                .filter(|volumes| !volumes.is_empty()),
+            author: format_author_list(&creators.authors, &SETTINGS),
+            authors: creators.authors,
+            editors: creators.editors,
+            translators: creators.translators,
+            item_type: data.item_type.clone(),
+            tags_filtered: filter_tags(&tags, &SETTINGS),
+            tags,
+            // Filled in by `papers` once collections are fetched
+            collection_path: None,
+            saved_at: parse_date(data.date_added.get(..10).unwrap_or_default())
+                .unwrap_or_else(chrono::Utc::now),
+            published_date,
+            publication_year: published_date.map(|date| date.year()),
+            // ISO 8601, e.g. 2024-01-05T12:34:56Z
+            date_accessed: data
+                .access_date
+                .as_deref()
+                .and_then(|date| parse_date(date.get(..10)?)),
+            attachment_path,
+            attachment_is_pdf: attachment_content_type.as_deref() == Some(PDF_CONTENT_TYPE),
+            attachment_content_type,
+            attachment_filename: attachment_db_path.as_deref().and_then(attachment_filename),
+            has_pdf: children.iter().any(|child| {
+                is_considered_attachment(child)
+                    && child.data.content_type.as_deref() == Some(PDF_CONTENT_TYPE)
+            }),
+            note_count: children
+                .iter()
+                .filter(|child| child.data.item_type == "note")
+                .count(),
+            has_highlights: false,
+            highlight_count: 0,
+            related: Vec::new(),
+        }
+    }
+}
+
+impl ZoteroBackend for LocalApiBackend {
+    fn papers(&self) -> Result<Vec<Paper>, Box<dyn Error>> {
+        let items = self.items()?;
+        let mut children: HashMap<&str, Vec<&ApiItem>> = HashMap::new();
+        for item in items {
+            if let Some(parent) = &item.data.parent_item {
+                children.entry(parent).or_default().push(item);
+            }
+        }
+
+        let collections: Vec<ApiCollection> = self.fetch_all("collections")?;
+        let collections_by_key: HashMap<&str, (&str, Option<&str>)> = collections
+            .iter()
+            .map(|collection| {
+                (
+                    collection.key.as_str(),
+                    (
+                        collection.data.name.as_str(),
+                        collection.data.parent_collection.as_str(),
+                    ),
+                )
+            })
+            .collect();
+
+        Ok(items
+            .iter()
+            .filter(|item| item.data.parent_item.is_none())
+            // Item IDs are not available through the API, only keys
+            .filter(|item| !SETTINGS.skip_zotero_keys.contains(&item.key))
+            .filter_map(|item| {
+                let item_children = children.get(item.key.as_str())?;
+                item_children
+                    .iter()
+                    .any(|child| is_considered_attachment(child))
+                    .then(|| {
+                        let mut paper = Self::api_paper(item, item_children);
+                        paper.collection_path = item
+                            .data
+                            .collections
+                            .iter()
+                            .map(|key| {
+                                collection_path(key.as_str(), |key| {
+                                    collections_by_key.get(key).copied()
+                                })
+                            })
+                            .min();
+                        paper
+                    })
+            })
+            .collect())
+    }
+
+    fn papers_modified_since(&self, since: DateTime<Utc>) -> Result<Vec<Paper>, Box<dyn Error>> {
+        let items = self.items()?;
+        let parents: HashMap<&str, &str> = items
+            .iter()
+            .filter_map(|item| Some((item.key.as_str(), item.data.parent_item.as_deref()?)))
+            .collect();
+        let mut modified_papers: HashSet<&str> = HashSet::new();
+        for item in items.iter().filter(|item| {
+            DateTime::parse_from_rfc3339(&item.data.date_modified).is_ok_and(|date| date > since)
+        }) {
+            // Annotations belong to an attachment, which belongs to the paper
+            let mut key = item.key.as_str();
+            while let Some(parent) = parents.get(key) {
+                key = parent;
+            }
+            modified_papers.insert(key);
+        }
+        Ok(self
+            .papers()?
+            .into_iter()
+            .filter(|paper| modified_papers.contains(paper.id.as_str()))
+            .collect())
+    }
+
+    fn highlights(&self) -> Result<HashMap<String, Vec<HighlightJson>>, Box<dyn Error>> {
+        let items = self.items()?;
+        // Annotations belong to an attachment, which belongs to the paper
+        let attachment_parents: HashMap<&str, &str> = items
+            .iter()
+            .filter(|item| item.data.item_type == "attachment")
+            .filter_map(|item| Some((item.key.as_str(), item.data.parent_item.as_deref()?)))
+            .collect();
+
+        let mut annotations: Vec<(&str, &ApiItem)> = items
+            .iter()
+            .filter(|item| item.data.item_type == "annotation")
+            .filter_map(|item| {
+                let attachment = item.data.parent_item.as_deref()?;
+                Some((*attachment_parents.get(attachment)?, item))
+            })
+            .collect();
+        // Sort indexes are zero-padded, e.g. 00012|003450|00321
+        annotations.sort_by(|(_, a), (_, b)| {
+            a.data
+                .annotation_sort_index
+                .cmp(&b.data.annotation_sort_index)
+        });
+
+        let mut highlights_map: HashMap<String, Vec<HighlightJson>> = HashMap::new();
+        let mut skipped_too_short = 0;
+        for (paper_key, annotation) in annotations {
+            let data = &annotation.data;
+            let annotation = Annotation {
+                // Item IDs are not available through the API
+                id: annotation.key.clone(),
+                key: annotation.key.clone(),
+                text: data.annotation_text.clone(),
+                comment: data.annotation_comment.clone(),
+                color: data.annotation_color.clone(),
+                sort_index: data.annotation_sort_index.clone().unwrap_or_default(),
+                date_added: data.date_added.get(..10).unwrap_or_default().to_string(),
+                date_modified: data.date_modified.get(..10).map(str::to_string),
+            };
+            if annotation.is_too_short() {
+                skipped_too_short += 1;
+                continue;
+            }
+            if let Some(highlight) = annotation.into_highlight() {
+                highlights_map
+                    .entry(paper_key.to_string())
+                    .or_default()
+                    .push(highlight);
+            }
+        }
+        if skipped_too_short > 0 {
+            tracing::debug!(
+                "Skipped {} highlights shorter than annotation_min_length",
+                skipped_too_short
+            );
+        }
+        Ok(highlights_map)
+    }
+
+    fn paper_highlights(&self, paper_id: &str) -> Result<Vec<HighlightJson>, Box<dyn Error>> {
+        let highlights_by_paper = self
+            .highlights_by_paper
+            .get_or_try_init(|| self.highlights())?;
+        Ok(highlights_by_paper
+            .get(paper_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn related_items(
+        &self,
+        paper_ids: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
+        let items = self.items()?;
+        let mut related_map: HashMap<String, Vec<String>> = HashMap::new();
+        for item in items.iter().filter(|item| paper_ids.contains(&item.key)) {
+            let uris = match item.data.relations.get("dc:relation") {
+                Some(serde_json::Value::String(uri)) => vec![uri.as_str()],
+                Some(serde_json::Value::Array(uris)) => {
+                    uris.iter().filter_map(serde_json::Value::as_str).collect()
+                }
+                _ => continue,
+            };
+            // Papers are identified by item key, the last segment of the URI
+            let related_keys = uris
+                .into_iter()
+                .filter_map(|uri| Some(uri.rsplit_once("/items/")?.1.to_string()));
+            related_map
+                .entry(item.key.clone())
+                .or_default()
+                .extend(related_keys);
+        }
+        Ok(related_map)
+    }
+
+    fn child_notes(&self, paper_id: &str) -> Result<Vec<NoteContent>, Box<dyn Error>> {
+        let mut notes: Vec<&ApiItem> = self
+            .items()?
+            .iter()
+            .filter(|item| {
+                item.data.item_type == "note" && item.data.parent_item.as_deref() == Some(paper_id)
+            })
+            .collect();
+        notes.sort_by(|a, b| a.data.date_added.cmp(&b.data.date_added));
+        Ok(notes
+            .into_iter()
+            .map(|note| NoteContent {
+                key: note.key.clone(),
+                html: note.data.note.clone().unwrap_or_default(),
+            })
+            .collect())
+    }
+}
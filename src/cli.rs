@@ -0,0 +1,140 @@
+use clap::{Parser, Subcommand};
+use clap_complete::Shell;
+use std::path::PathBuf;
+
+use crate::migrations::Version;
+use crate::output::{ColorChoice, OutputFormat};
+
+#[derive(Debug, Parser)]
+#[command(about = "Sync Zotero papers and their highlights into org-roam files")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Ask for confirmation before creating or editing each file
+    #[arg(long)]
+    pub interactive: bool,
+
+    /// Automatically answer "yes" to all confirmation prompts of --interactive, for scripting.
+    /// --dry-run and --assert-no-edits still write nothing
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Compare the generated content with the existing org files instead of writing them.
+    /// Prints a diff and exits with code 1 if any file would be created or changed
+    #[arg(long, conflicts_with = "interactive")]
+    pub assert_no_edits: bool,
+
+    /// Create org files with the paper's metadata but no highlights, and leave existing
+    /// files untouched
+    #[arg(long, conflicts_with = "compare_db_to_files")]
+    pub no_highlights: bool,
+
+    /// Only consider papers with at least one highlight or note in Zotero
+    #[arg(long)]
+    pub only_with_highlights: bool,
+
+    /// Compare the highlights section of each existing org file with the one generated from
+    /// the Zotero database, reporting the files out of sync, without writing anything.
+    /// Exits with code 1 if any file is out of sync
+    #[arg(long, conflicts_with_all = ["interactive", "assert_no_edits", "only_new"])]
+    pub compare_db_to_files: bool,
+
+    /// Only consider papers that don't have an org file yet, leaving existing files untouched
+    #[arg(long)]
+    pub only_new: bool,
+
+    /// Only consider papers modified in Zotero after DATE (YYYY-MM-DD or "YYYY-MM-DD HH:MM:SS",
+    /// in UTC), including papers that got new highlights
+    #[arg(long, value_name = "DATE")]
+    pub filter_since: Option<String>,
+
+    /// Don't run emacs_eval_after_sync with emacs_command after files are created or edited,
+    /// e.g. in cron jobs where no Emacs server is running
+    #[arg(long)]
+    pub skip_emacs_eval: bool,
+
+    /// Print all papers as JSON to stdout and exit without writing any files
+    #[arg(long, conflicts_with = "stats_only")]
+    pub export_json: bool,
+
+    /// Write all papers as an org table to OUTPUT, newest first, and exit without touching
+    /// the org-roam files
+    #[arg(long, value_name = "OUTPUT", conflicts_with_all = ["stats_only", "export_json"])]
+    pub export_org_table: Option<PathBuf>,
+
+    /// Check that the Zotero database has the expected tables and columns before running.
+    /// This is done automatically on the first run, when the org-roam directory has no
+    /// .org-zotero.state file yet
+    #[arg(long)]
+    pub validate_db: bool,
+
+    /// Print statistics about the Zotero library and exit without writing any files
+    #[arg(long)]
+    pub stats_only: bool,
+
+    /// Print the version, the detected Zotero schema version and the templates directory
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// Print a shell completion script to stdout and exit
+    #[arg(
+        long,
+        value_name = "SHELL",
+        long_help = "Print a completion script for SHELL to stdout and exit.\n\n\
+            Installation examples:\n  \
+            bash: org-zotero-rust --generate-completions bash > ~/.local/share/bash-completion/completions/org-zotero-rust\n  \
+            zsh: org-zotero-rust --generate-completions zsh > ~/.zsh/completions/_org-zotero-rust\n  \
+            fish: org-zotero-rust --generate-completions fish > ~/.config/fish/completions/org-zotero-rust.fish\n  \
+            PowerShell: org-zotero-rust --generate-completions powershell >> $PROFILE"
+    )]
+    pub generate_completions: Option<Shell>,
+
+    /// Print a JSON Schema for config.toml to stdout and exit (needs the `schema` feature)
+    #[arg(long)]
+    pub generate_config_schema: bool,
+
+    /// Rewrite existing org files from the format of one org-zotero-rust version to another
+    #[arg(long, num_args = 2, value_names = ["FROM_VERSION", "TO_VERSION"])]
+    pub migrate: Option<Vec<Version>>,
+
+    /// Print the files that would be created or edited, or migrated with --migrate, without
+    /// writing them. With dry_run_output_dir set, write them to a new subdirectory of it instead
+    #[arg(long, conflicts_with_all = ["interactive", "assert_no_edits", "compare_db_to_files"])]
+    pub dry_run: bool,
+
+    /// Print every SQL query and its parameters to stderr before running it
+    #[arg(short = 'S', long)]
+    pub verbose_sql: bool,
+
+    /// Print the SQL queries of papers and highlights, with the filters of the settings filled in, and exit
+    #[arg(long)]
+    pub print_sql: bool,
+
+    /// Format of the progress output written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    /// When to color terminal output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Never color terminal output, same as --color never
+    #[arg(long, conflicts_with = "color")]
+    pub no_color: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Parse and render every template with sample data, reporting errors per file.
+    /// Exits with code 1 if any template fails.
+    CheckTemplates,
+    /// Check that the targets of the `[[file:...]]` links in generated org files exist,
+    /// warning about each broken link. Exits with code 1 if any is broken.
+    CheckLinks,
+    /// Rescan org_roam_dir for refs and remove the stale entries of org-roam's database
+    /// (org_roam_db_path) by running emacs_command. Exits with code 1 if any is left.
+    Reindex,
+    /// List the templates in templates_dir, what they are used for, and syntax errors
+    ListTemplates,
+}
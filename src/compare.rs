@@ -0,0 +1,53 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Hash of a highlights section, ignoring trailing whitespace as updates do.
+pub fn section_hash(section: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for line in section.trim().lines() {
+        line.trim_end().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Number of highlights that are only in one of the sections or differ
+/// between them. Highlights are matched by their `zotero:<id>` line.
+pub fn differing_highlights(expected: &str, actual: &str) -> usize {
+    let expected = highlight_hashes(expected);
+    let actual = highlight_hashes(actual);
+    let changed_or_removed = expected
+        .iter()
+        .filter(|(id, hash)| actual.get(*id) != Some(hash))
+        .count();
+    let added = actual
+        .keys()
+        .filter(|id| !expected.contains_key(*id))
+        .count();
+    changed_or_removed + added
+}
+
+/// Hash of the lines of each highlight of a section, by highlight ID.
+fn highlight_hashes(section: &str) -> HashMap<&str, u64> {
+    let mut blocks: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut current = None;
+    for line in section.lines() {
+        if let Some(id) = highlight_id(line) {
+            current = Some(id);
+        }
+        if let Some(id) = current {
+            blocks.entry(id).or_default().push(line.trim_end());
+        }
+    }
+    blocks
+        .into_iter()
+        .map(|(id, lines)| (id, section_hash(&lines.join("\n"))))
+        .collect()
+}
+
+/// The ID of the highlight starting at `line`, e.g. `zotero:123` for
+/// `** zotero:123 [possible duplicate]`, also without stars in drawers.
+fn highlight_id(line: &str) -> Option<&str> {
+    let id = line.trim_start_matches('*').split_whitespace().next()?;
+    (id.starts_with("zotero:") && id != "zotero:highlights").then_some(id)
+}
@@ -0,0 +1,59 @@
+/// Returns a line diff of `old` and `new`: unchanged lines are prefixed with
+/// two spaces, removed lines with `- ` and added lines with `+ `.
+/// Only changed lines and up to `context` lines around them are kept.
+pub fn line_diff(old: &str, new: &str, context: usize) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of
+    // old_lines[i..] and new_lines[j..]
+    let mut lcs = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            lcs[i][j] = if old_lines[i] == new_lines[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops: Vec<(char, &str)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            ops.push((' ', old_lines[i]));
+            i += 1;
+            j += 1;
+        } else if i < old_lines.len() && (j == new_lines.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', old_lines[i]));
+            i += 1;
+        } else {
+            ops.push(('+', new_lines[j]));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (kind, _))| *kind != ' ')
+        .map(|(index, _)| index)
+        .collect();
+    let mut output = String::new();
+    let mut last_printed: Option<usize> = None;
+    for (index, (kind, line)) in ops.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|&change| index + context >= change && index <= change + context);
+        if !near_change {
+            continue;
+        }
+        if last_printed.is_some_and(|last| index > last + 1) {
+            output.push_str("  ...\n");
+        }
+        output.push_str(&format!("{} {}\n", kind, line));
+        last_printed = Some(index);
+    }
+    output
+}
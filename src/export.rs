@@ -0,0 +1,192 @@
+use serde::Serialize;
+
+use crate::{Author, Paper};
+
+/// Version of the `--export-json` layout. Bump it when a field is removed or
+/// changes meaning; adding a field is backward compatible and doesn't need a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A paper as written by `--export-json`.
+///
+/// Kept separate from `Paper` so that the JSON layout only changes on purpose:
+/// fields of `Paper` that are renamed or removed must keep being filled in here.
+#[derive(Debug, Serialize)]
+pub struct SerializedPaper {
+    pub schema_version: u32,
+    pub id: String,
+    pub title: String,
+    pub title_original: String,
+    pub short_title: Option<String>,
+    pub series: Option<String>,
+    pub series_number: Option<String>,
+    pub pages: Option<String>,
+    pub page_count: Option<u32>,
+    pub volume_number: Option<String>,
+    pub number_of_volumes: Option<String>,
+    pub authors: Vec<SerializedAuthor>,
+    pub editors: Vec<SerializedAuthor>,
+    pub translators: Vec<SerializedAuthor>,
+    pub item_type: String,
+    pub tags: Vec<String>,
+    pub collection_path: Option<String>,
+    pub url: Option<String>,
+    pub roam_ref: String,
+    pub zotero_url: String,
+    pub item_key: String,
+    // Dates as YYYY-MM-DD
+    pub saved_at: String,
+    pub published_date: Option<String>,
+    pub date_accessed: Option<String>,
+    pub attachment_path: Option<String>,
+    pub attachment_content_type: Option<String>,
+    pub has_pdf: bool,
+    pub note_count: usize,
+    pub highlight_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SerializedAuthor {
+    pub name: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub orcid: Option<String>,
+}
+
+impl From<&Author> for SerializedAuthor {
+    fn from(author: &Author) -> Self {
+        SerializedAuthor {
+            name: author.name.clone(),
+            first_name: author.first_name.clone(),
+            last_name: author.last_name.clone(),
+            orcid: author.orcid.clone(),
+        }
+    }
+}
+
+impl From<&Paper> for SerializedPaper {
+    fn from(paper: &Paper) -> Self {
+        SerializedPaper {
+            schema_version: SCHEMA_VERSION,
+            id: paper.id.clone(),
+            title: paper.title.clone(),
+            title_original: paper.title_original.clone(),
+            short_title: paper.short_title.clone(),
+            series: paper.series.clone(),
+            series_number: paper.series_number.clone(),
+            pages: paper.pages.clone(),
+            page_count: paper.page_count,
+            volume_number: paper.volume_number.clone(),
+            number_of_volumes: paper.number_of_volumes.clone(),
+            authors: paper.authors.iter().map(SerializedAuthor::from).collect(),
+            editors: paper.editors.iter().map(SerializedAuthor::from).collect(),
+            translators: paper
+                .translators
+                .iter()
+                .map(SerializedAuthor::from)
+                .collect(),
+            item_type: paper.item_type.clone(),
+            tags: paper.tags.clone(),
+            collection_path: paper.collection_path.clone(),
+            url: paper.has_url.then(|| paper.source_url.clone()),
+            roam_ref: paper.roam_ref.clone(),
+            zotero_url: paper.zotero_url.clone(),
+            item_key: paper.item_key.clone(),
+            saved_at: paper.saved_at.format("%Y-%m-%d").to_string(),
+            published_date: paper
+                .published_date
+                .map(|date| date.format("%Y-%m-%d").to_string()),
+            date_accessed: paper
+                .date_accessed
+                .map(|date| date.format("%Y-%m-%d").to_string()),
+            attachment_path: paper
+                .attachment_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            attachment_content_type: paper.attachment_content_type.clone(),
+            has_pdf: paper.has_pdf,
+            note_count: paper.note_count,
+            highlight_count: paper.highlight_count,
+        }
+    }
+}
+
+/// Prints `papers` to stdout as a pretty-printed JSON array.
+pub fn print_papers(papers: &[Paper]) -> serde_json::Result<()> {
+    let serialized: Vec<SerializedPaper> = papers.iter().map(SerializedPaper::from).collect();
+    println!("{}", serde_json::to_string_pretty(&serialized)?);
+    Ok(())
+}
+
+/// Columns of `--export-org-table`.
+const ORG_TABLE_HEADER: [&str; 7] = [
+    "Title",
+    "Author",
+    "Year",
+    "Type",
+    "Tags",
+    "Has-PDF",
+    "Highlights",
+];
+
+/// The papers as an aligned org table, newest first, for `--export-org-table`.
+/// Papers without a publication date come last; ties are sorted by title.
+pub fn org_table(papers: &[Paper]) -> String {
+    let mut papers: Vec<&Paper> = papers.iter().collect();
+    papers.sort_by(|a, b| {
+        b.publication_year
+            .cmp(&a.publication_year)
+            .then_with(|| a.title.to_lowercase().cmp(&b.title.to_lowercase()))
+    });
+
+    let rows: Vec<[String; 7]> = papers
+        .iter()
+        .map(|paper| {
+            [
+                paper.title.clone(),
+                paper.author.clone(),
+                paper
+                    .publication_year
+                    .map(|year| year.to_string())
+                    .unwrap_or_default(),
+                paper.item_type.clone(),
+                paper.tags_filtered.join(", "),
+                if paper.has_pdf { "yes" } else { "no" }.to_string(),
+                paper.highlight_count.to_string(),
+            ]
+            .map(|cell| org_table_cell(&cell))
+        })
+        .collect();
+
+    let mut widths = ORG_TABLE_HEADER.map(|header| header.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: &[String]| -> String {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!(" {:<width$} ", cell, width = width))
+            .collect();
+        format!("|{}|\n", cells.join("|"))
+    };
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(width + 2)).collect();
+
+    let mut table = format_row(&ORG_TABLE_HEADER.map(str::to_string));
+    table.push_str(&format!("|{}|\n", separator.join("+")));
+    for row in &rows {
+        table.push_str(&format_row(row));
+    }
+    table
+}
+
+/// A table cell on one line, with `|` written as the `\vert` entity so that it
+/// doesn't end the cell.
+fn org_table_cell(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\vert{}")
+}
@@ -0,0 +1,372 @@
+/// Converts the HTML of a Zotero note to org markup: paragraphs, line breaks,
+/// lists, quotes, code blocks, links and emphasis. Headings become bold lines,
+/// so that notes can't add headings to the file. Other tags are dropped and
+/// their text kept.
+pub fn html_to_org(html: &str) -> String {
+    let mut converter = Converter::default();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        converter.text(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        converter.tag(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+    converter.text(rest);
+    converter.finish()
+}
+
+#[derive(Default)]
+struct Converter {
+    out: String,
+    // One entry per open list, with the next number for ordered lists
+    lists: Vec<Option<usize>>,
+    // Targets of the open links, None for anchors without href
+    links: Vec<Option<String>>,
+    // Depth of <pre>, whose whitespace is kept
+    pre: usize,
+    // Depth of <script> and <style>, whose content is dropped
+    skipped: usize,
+    // Opening emphasis markers, written with the next text so that they touch it
+    pending_markers: String,
+}
+
+impl Converter {
+    fn text(&mut self, text: &str) {
+        if self.skipped > 0 || text.is_empty() {
+            return;
+        }
+        let text = decode_entities(text);
+        if self.pre > 0 {
+            self.flush_markers();
+            self.out.push_str(&text);
+            return;
+        }
+        let at_line_start = self.out.is_empty() || self.out.ends_with([' ', '\n']);
+        let mut collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.starts_with(char::is_whitespace) && !at_line_start && !collapsed.is_empty() {
+            collapsed.insert(0, ' ');
+        }
+        if text.ends_with(char::is_whitespace) && !collapsed.is_empty() {
+            collapsed.push(' ');
+        }
+        if collapsed.is_empty() && !at_line_start && !text.is_empty() {
+            collapsed.push(' ');
+        }
+        if !self.pending_markers.is_empty() && !collapsed.trim().is_empty() {
+            if collapsed.starts_with(' ') {
+                self.out.push(' ');
+            }
+            self.flush_markers();
+            self.out.push_str(collapsed.trim_start());
+        } else {
+            self.out.push_str(&collapsed);
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        let tag = tag.trim();
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let attributes = &tag[name_end..];
+        let opens_emphasis = !closing
+            && matches!(
+                name.as_str(),
+                "strong" | "b" | "em" | "i" | "u" | "s" | "del" | "strike" | "code"
+            );
+        if !opens_emphasis {
+            self.flush_markers();
+        }
+
+        match (name.as_str(), closing) {
+            ("script" | "style", false) => self.skipped += 1,
+            ("script" | "style", true) => self.skipped = self.skipped.saturating_sub(1),
+            _ if self.skipped > 0 => {}
+            ("br", _) => {
+                self.trim_trailing_spaces();
+                self.out.push('\n');
+            }
+            ("p" | "div" | "table" | "hr", _) if self.lists.is_empty() => self.block_break(true),
+            ("tr", _) => self.block_break(false),
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                self.block_break(true);
+                self.out.push('*');
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => {
+                self.trim_trailing_spaces();
+                self.out.push('*');
+                self.block_break(true);
+            }
+            ("ul", false) => {
+                self.block_break(self.lists.is_empty());
+                self.lists.push(None);
+            }
+            ("ol", false) => {
+                self.block_break(self.lists.is_empty());
+                self.lists.push(Some(1));
+            }
+            ("ul" | "ol", true) => {
+                self.lists.pop();
+                self.block_break(self.lists.is_empty());
+            }
+            ("li", false) => {
+                self.block_break(false);
+                let depth = self.lists.len().max(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.out.push_str(&"  ".repeat(depth - 1));
+                self.out.push_str(&marker);
+            }
+            ("blockquote", false) => {
+                self.block_break(true);
+                self.out.push_str("#+begin_quote\n");
+            }
+            ("blockquote", true) => {
+                self.block_break(false);
+                self.out.push_str("#+end_quote");
+                self.block_break(true);
+            }
+            ("pre", false) => {
+                self.block_break(true);
+                self.out.push_str("#+begin_example\n");
+                self.pre += 1;
+            }
+            ("pre", true) => {
+                self.pre = self.pre.saturating_sub(1);
+                self.block_break(false);
+                self.out.push_str("#+end_example");
+                self.block_break(true);
+            }
+            ("a", false) => {
+                let href = attribute(attributes, "href");
+                if href.is_some() {
+                    self.out.push_str("[[");
+                    self.out.push_str(href.as_deref().unwrap_or_default());
+                    self.out.push_str("][");
+                }
+                self.links.push(href);
+            }
+            ("a", true) => {
+                if let Some(Some(_)) = self.links.pop() {
+                    self.trim_trailing_spaces();
+                    self.out.push_str("]]");
+                }
+            }
+            ("strong" | "b", _) => self.emphasis('*', closing),
+            ("em" | "i", _) => self.emphasis('/', closing),
+            ("u", _) => self.emphasis('_', closing),
+            ("s" | "del" | "strike", _) => self.emphasis('+', closing),
+            ("code", _) if self.pre == 0 => self.emphasis('~', closing),
+            _ => {}
+        }
+    }
+
+    /// Org emphasis markers must touch the text they surround.
+    fn emphasis(&mut self, marker: char, closing: bool) {
+        if closing {
+            let trailing_space = self.out.ends_with(' ');
+            self.trim_trailing_spaces();
+            self.out.push(marker);
+            if trailing_space {
+                self.out.push(' ');
+            }
+        } else {
+            self.pending_markers.push(marker);
+        }
+    }
+
+    fn flush_markers(&mut self) {
+        self.out
+            .push_str(&std::mem::take(&mut self.pending_markers));
+    }
+
+    /// Ends the current line, and leaves an empty line after it with `blank`.
+    fn block_break(&mut self, blank: bool) {
+        if self.pre > 0 {
+            return;
+        }
+        self.trim_trailing_spaces();
+        if self.out.is_empty() {
+            return;
+        }
+        if !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+        if blank && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn trim_trailing_spaces(&mut self) {
+        let trimmed_len = self.out.trim_end_matches([' ', '\t']).len();
+        self.out.truncate(trimmed_len);
+    }
+
+    fn finish(mut self) -> String {
+        self.flush_markers();
+        let mut lines: Vec<&str> = Vec::new();
+        for line in self.out.lines().map(str::trim_end) {
+            // At most one empty line in a row, and none at the edges of quotes
+            if line.is_empty()
+                && lines
+                    .last()
+                    .is_none_or(|last| last.is_empty() || *last == "#+begin_quote")
+            {
+                continue;
+            }
+            if line == "#+end_quote" && lines.last().is_some_and(|last| last.is_empty()) {
+                lines.pop();
+            }
+            lines.push(line);
+        }
+        lines.join("\n").trim().to_string()
+    }
+}
+
+/// The value of an attribute in the attributes of a tag, e.g. `href` in
+/// ` href="https://example.com" rel="noopener"`.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let mut rest = attributes;
+    while let Some(start) = rest.find(name) {
+        let before = rest[..start].chars().next_back();
+        rest = &rest[start + name.len()..];
+        if before.is_some_and(|c| !c.is_whitespace()) {
+            continue;
+        }
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default(),
+            _ => value.split_whitespace().next().unwrap_or_default(),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+/// Decodes the named entities Zotero writes in notes and numeric ones.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let replacement = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, replacement) {
+            (Some(entity), Some(replacement)) => {
+                decoded.push(replacement);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_named_and_numeric_entities() {
+        assert_eq!(
+            html_to_org("<p>Caf&#xE9; &amp; b&#233;b&#xe9;, AT&T &bogus;</p>"),
+            "Café & bébé, AT&T &bogus;"
+        );
+    }
+
+    #[test]
+    fn nests_ordered_lists_in_unordered_ones() {
+        assert_eq!(
+            html_to_org(
+                "<ul><li>Apples<ol><li>Fuji</li><li>Gala</li></ol></li><li>Pears</li></ul>"
+            ),
+            "- Apples\n  1. Fuji\n  2. Gala\n- Pears"
+        );
+    }
+
+    #[test]
+    fn keeps_links_inside_emphasis() {
+        assert_eq!(
+            html_to_org(
+                r#"<p>See <strong><a href="https://example.com/?a=1&amp;b=2">the paper</a></strong>.</p>"#
+            ),
+            "See *[[https://example.com/?a=1&b=2][the paper]]*."
+        );
+    }
+
+    #[test]
+    fn keeps_the_text_of_anchors_without_href() {
+        assert_eq!(
+            html_to_org(r#"<p><a name="intro">Intro</a> text</p>"#),
+            "Intro text"
+        );
+    }
+
+    #[test]
+    fn moves_spaces_out_of_emphasis() {
+        assert_eq!(
+            html_to_org("<p><em>word </em>next <b> bold</b> and<b><i> both</i></b></p>"),
+            "/word/ next *bold* and */both/*"
+        );
+    }
+
+    #[test]
+    fn keeps_the_whitespace_of_pre() {
+        assert_eq!(
+            html_to_org("<p>Code:</p><pre>fn main() {\n    run();\n}</pre>"),
+            "Code:\n\n#+begin_example\nfn main() {\n    run();\n}\n#+end_example"
+        );
+    }
+
+    #[test]
+    fn collapses_blank_lines() {
+        assert_eq!(
+            html_to_org("<p>One</p><p></p><p>Two</p><br><br><br><p>Three</p>"),
+            "One\n\nTwo\n\nThree"
+        );
+    }
+
+    #[test]
+    fn turns_headings_into_bold_lines_and_drops_scripts() {
+        assert_eq!(
+            html_to_org("<h2>Summary</h2><script>alert(1)</script><p>Text</p>"),
+            "*Summary*\n\nText"
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::path::{Path, PathBuf};
+
+/// A `[[file:...]]` link found in an org file.
+#[derive(Debug)]
+pub struct FileLink {
+    /// 1-based line number of the link
+    pub line: usize,
+    /// Link target as written, without the `file:` prefix and `::` search option
+    pub target: String,
+}
+
+/// Returns the `[[file:...]]` links of `content`, in order.
+pub fn file_links(content: &str) -> Vec<FileLink> {
+    let mut links = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find("[[file:") {
+            rest = &rest[start + "[[file:".len()..];
+            let Some(end) = rest.find(']') else {
+                break;
+            };
+            let target = &rest[..end];
+            // org allows a search option after the path, e.g. file:paper.pdf::12
+            let target = target.split_once("::").map_or(target, |(path, _)| path);
+            if !target.is_empty() {
+                links.push(FileLink {
+                    line: index + 1,
+                    target: target.to_string(),
+                });
+            }
+            rest = &rest[end..];
+        }
+    }
+    links
+}
+
+/// Resolves a link target the way org does: `~` is the home directory and
+/// relative paths are relative to the directory of the org file.
+pub fn resolve_link_target(target: &str, org_file: &Path, home_dir: &Path) -> PathBuf {
+    let path = match target.strip_prefix("~/") {
+        Some(rest) => home_dir.join(rest),
+        None => PathBuf::from(target),
+    };
+    if path.is_relative() {
+        org_file
+            .parent()
+            .map_or_else(|| path.clone(), |dir| dir.join(&path))
+    } else {
+        path
+    }
+}
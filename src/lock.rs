@@ -0,0 +1,45 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILENAME: &str = ".org-zotero.lock";
+
+/// Lock file preventing concurrent runs on the same org-roam directory.
+/// The file holds the PID of the owner and is removed when the guard is dropped.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Creates the lock file in `dir`, failing if it already exists.
+    pub fn acquire(dir: &Path) -> io::Result<LockFile> {
+        let path = dir.join(LOCK_FILENAME);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let owner = fs::read_to_string(&path).unwrap_or_default();
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already exists (held by PID {}). If no other instance is running, remove it.",
+                        path.display(),
+                        owner.trim()
+                    ),
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+        let lock = LockFile { path };
+        writeln!(file, "{}", std::process::id())?;
+        Ok(lock)
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
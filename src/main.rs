@@ -1,15 +1,48 @@
+mod backend;
+mod cli;
+mod compare;
+mod diff;
+mod export;
+mod html;
+mod links;
+mod lock;
+mod migrations;
+mod output;
+mod pool;
+mod prompt;
+mod schema;
 mod settings;
+mod sort;
+mod templates;
+#[cfg(test)]
+mod test_helpers;
 
-use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
-use rusqlite::{Connection, Result, Row};
+use backend::{LocalApiBackend, SqliteBackend, ZoteroBackend};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Commands};
+use lock::LockFile;
+use migrations::Version;
+use output::{ColorChoice, Event, Reporter, RunError, RunSummary};
+use pool::ConnectionPool;
+use prompt::{Decision, FileAction, Prompter};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OptionalExtension, Result, Row, Statement, ToSql};
 use serde::Serialize;
-use settings::SETTINGS;
-use std::collections::HashMap;
+use settings::{
+    FileEncoding, HighlightFormat, Settings, TitleCaseMode, ZoteroUriScheme, SETTINGS,
+    UUID_NAMESPACE,
+};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tera::{Context, Tera};
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize)]
@@ -19,6 +52,56 @@ pub struct Highlight {
     pub content: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Author {
+    pub first_name: String,
+    pub last_name: String,
+    // Display name, "first last" or just "last" for single-field creators
+    pub name: String,
+    pub orcid: Option<String>,
+    // `creatorID` of the SQLite backend, shared by the roles of one creator
+    #[serde(skip)]
+    pub creator_id: Option<i64>,
+}
+
+impl Author {
+    fn new(first_name: &str, last_name: &str) -> Self {
+        Author {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            name: format!("{} {}", first_name, last_name).trim().to_string(),
+            orcid: None,
+            creator_id: None,
+        }
+    }
+}
+
+/// Creators of an item by type, as returned by `split_creators`.
+#[derive(Debug, Default)]
+pub struct Creators {
+    pub authors: Vec<Author>,
+    pub editors: Vec<Author>,
+    pub translators: Vec<Author>,
+}
+
+/// Splits creators by their creator type, e.g. `author` or `editor`: those of a
+/// type of the `creator_types` setting are the authors, and editors and
+/// translators are kept apart too.
+pub fn split_creators(creators: Vec<(String, Author)>, settings: &Settings) -> Creators {
+    let mut split = Creators::default();
+    for (creator_type, creator) in creators {
+        match creator_type.as_str() {
+            "editor" => split.editors.push(creator.clone()),
+            "translator" => split.translators.push(creator.clone()),
+            _ => {}
+        }
+        if settings.creator_types.contains(&creator_type) {
+            split.authors.push(creator);
+        }
+    }
+    split
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Paper {
     pub id: String,
@@ -26,22 +109,338 @@ pub struct Paper {
     // roam_ref is either the full URL if there is one, or a ref in the format @zotero_<id>
     pub roam_ref: String,
     pub source_url: String,
+    // Opens the item in the Zotero app, e.g. zotero://select/library/items/ABCD2345
     pub zotero_url: String,
+    // Zotero item key, stable across Zotero installations unlike the numeric id
+    pub item_key: String,
     pub title: String,
+    // Title before `title_case` is applied
+    pub title_original: String,
+    // Zotero's "Short Title" field
+    pub short_title: Option<String>,
+    // Book series, e.g. "Lecture Notes in Computer Science", and number in it
+    pub series: Option<String>,
+    pub series_number: Option<String>,
+    // Page range of articles, e.g. "123-145", and number of pages of books
+    pub pages: Option<String>,
+    pub page_count: Option<u32>,
+    // Volume of multi-volume works, e.g. "2", and their number of volumes, e.g. "5"
+    pub volume_number: Option<String>,
+    pub number_of_volumes: Option<String>,
     pub author: String,
+    // Creators of the `creator_types` setting, authors by default
+    pub authors: Vec<Author>,
+    pub editors: Vec<Author>,
+    pub translators: Vec<Author>,
+    pub item_type: String,
+    // Zotero tags, and the same without internal ones like "_tablet", see `filter_tags`
+    pub tags: Vec<String>,
+    pub tags_filtered: Vec<String>,
+    // Path of the paper's collection, e.g. "Research/ML", the first one
+    // alphabetically when it is in several
+    pub collection_path: Option<String>,
     pub saved_at: DateTime<Utc>,
     pub published_date: Option<DateTime<Utc>>,
+    // Year of `published_date`, e.g. for "Smith, 2023"
+    pub publication_year: Option<i32>,
+    // When a web page was accessed, Zotero's "Accessed" field
+    pub date_accessed: Option<DateTime<Utc>>,
+    // Absolute path of the paper's attachment file, preferring PDFs, if it exists on disk
+    pub attachment_path: Option<PathBuf>,
+    // MIME type of the attachment, e.g. application/pdf, application/epub+zip, text/html
+    pub attachment_content_type: Option<String>,
+    pub attachment_is_pdf: bool,
+    pub attachment_filename: Option<String>,
+    // Whether any attachment in Zotero is a PDF, even if the file is missing from disk
+    pub has_pdf: bool,
+    // Number of Zotero child notes (not annotations)
+    pub note_count: usize,
+    // Set by `set_highlight_counts` once highlights are queried
+    pub has_highlights: bool,
+    pub highlight_count: usize,
+    // Set by `set_related_papers` once relations are queried
+    pub related: Vec<RelatedPaper>,
+}
+
+/// A paper marked as related in Zotero, e.g. with "Related" in the item pane.
+#[derive(Debug, Clone, Serialize)]
+pub struct RelatedPaper {
+    pub roam_ref: String,
+    pub title: String,
+}
+
+/// Builds a `Paper` with placeholder values for every field that is not set,
+/// so that code constructing papers by hand keeps working when fields are added.
+/// The fields derived from settings, `author` and `tags_filtered`, are computed
+/// by `build` from the settings it is given.
+#[derive(Debug, Clone)]
+pub struct PaperBuilder {
+    paper: Paper,
+}
+
+impl Default for PaperBuilder {
+    fn default() -> Self {
+        PaperBuilder {
+            paper: Paper {
+                id: "1".to_string(),
+                has_url: false,
+                roam_ref: "@zotero_1".to_string(),
+                source_url: String::new(),
+                zotero_url: "zotero://select/library/items/ABCD1234".to_string(),
+                item_key: "ABCD1234".to_string(),
+                title: "Untitled".to_string(),
+                title_original: "Untitled".to_string(),
+                short_title: None,
+                series: None,
+                series_number: None,
+                pages: None,
+                page_count: None,
+                volume_number: None,
+                number_of_volumes: None,
+                author: String::new(),
+                authors: Vec::new(),
+                editors: Vec::new(),
+                translators: Vec::new(),
+                item_type: "journalArticle".to_string(),
+                tags: Vec::new(),
+                tags_filtered: Vec::new(),
+                collection_path: None,
+                saved_at: Utc::now(),
+                published_date: None,
+                publication_year: None,
+                date_accessed: None,
+                attachment_path: None,
+                attachment_content_type: None,
+                attachment_is_pdf: false,
+                has_pdf: false,
+                attachment_filename: None,
+                note_count: 0,
+                has_highlights: false,
+                highlight_count: 0,
+                related: Vec::new(),
+            },
+        }
+    }
+}
+
+impl PaperBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.paper.title = title.to_string();
+        self.paper.title_original = title.to_string();
+        self
+    }
+
+    pub fn short_title(mut self, short_title: &str) -> Self {
+        self.paper.short_title = Some(short_title.to_string());
+        self
+    }
+
+    pub fn author(mut self, first_name: &str, last_name: &str, orcid: Option<&str>) -> Self {
+        self.paper.authors.push(Author {
+            orcid: orcid.map(str::to_string),
+            ..Author::new(first_name, last_name)
+        });
+        self
+    }
+
+    pub fn editor(mut self, first_name: &str, last_name: &str) -> Self {
+        self.paper.editors.push(Author::new(first_name, last_name));
+        self
+    }
+
+    pub fn translator(mut self, first_name: &str, last_name: &str) -> Self {
+        self.paper
+            .translators
+            .push(Author::new(first_name, last_name));
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.paper.tags.push(tag.to_string());
+        self
+    }
+
+    /// Sets the source URL, which also becomes the roam ref.
+    pub fn url(mut self, url: &str) -> Self {
+        self.paper.has_url = true;
+        self.paper.source_url = url.to_string();
+        self.paper.roam_ref = url.to_string();
+        self
+    }
+
+    pub fn published_date(mut self, published_date: DateTime<Utc>) -> Self {
+        self.paper.published_date = Some(published_date);
+        self.paper.publication_year = Some(published_date.year());
+        self
+    }
+
+    pub fn attachment(mut self, path: impl Into<PathBuf>, content_type: &str) -> Self {
+        let path = path.into();
+        self.paper.attachment_filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        self.paper.attachment_path = Some(path);
+        self.paper.attachment_is_pdf = content_type == PDF_CONTENT_TYPE;
+        self.paper.has_pdf = content_type == PDF_CONTENT_TYPE;
+        self.paper.attachment_content_type = Some(content_type.to_string());
+        self
+    }
+
+    pub fn note_count(mut self, note_count: usize) -> Self {
+        self.paper.note_count = note_count;
+        self
+    }
+
+    pub fn highlight_count(mut self, highlight_count: usize) -> Self {
+        self.paper.highlight_count = highlight_count;
+        self.paper.has_highlights = highlight_count > 0;
+        self
+    }
+
+    pub fn build(mut self, settings: &Settings) -> Paper {
+        self.paper.author = format_author_list(&self.paper.authors, settings);
+        self.paper.tags_filtered = filter_tags(&self.paper.tags, settings);
+        self.paper
+    }
+}
+
+impl Paper {
+    /// The title new file names are generated from.
+    pub fn filename_title(&self) -> &str {
+        match &self.short_title {
+            Some(short_title) if SETTINGS.use_short_title_for_filename => short_title,
+            _ => &self.title,
+        }
+    }
+
+    /// The start of new file names with `author_field_for_filename`, e.g.
+    /// `smith-2023` from the first author's last name and the publication
+    /// year, or `None` when the paper has no author.
+    pub fn filename_author_prefix(&self) -> Option<String> {
+        if !SETTINGS.author_field_for_filename {
+            return None;
+        }
+        let last_name = slug::slugify(&self.authors.first()?.last_name);
+        if last_name.is_empty() {
+            return None;
+        }
+        Some(match self.publication_year {
+            Some(year) => format!("{}-{}", last_name, year),
+            None => last_name,
+        })
+    }
+
+    /// The length of the paper, "248 pp." when the number of pages is known,
+    /// e.g. for books, and "pp. 123–145" for articles with a page range.
+    pub fn length_display(&self) -> Option<String> {
+        match (self.page_count, &self.pages) {
+            (Some(page_count), _) => Some(format!("{} pp.", page_count)),
+            (None, Some(pages)) => Some(format!("pp. {}", pages.replace('-', "–"))),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AnnotationType {
+    Highlight,
+    // A sticky note with a comment but no highlighted text
+    StandaloneNote,
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct HighlightJson {
     id: String,
+    // Zotero item key, stable across Zotero installations unlike the numeric id
+    annotation_key: String,
     content: String,
+    // Characters in the highlighted text before `max_highlight_chars` truncated it
+    highlight_original_length: usize,
     note: String,
     note_saved_at: String,
+    possible_duplicate: bool,
+    annotation_type: AnnotationType,
+    // Highlight color, e.g. #ffd400
+    color: Option<String>,
+    // Position in the document, zero-padded so that it sorts as a string
+    sort_index: String,
+    date_added: String,
+}
+
+/// A child note of a paper, as HTML.
+#[derive(Debug, Clone)]
+pub struct NoteContent {
+    // Zotero item key of the note
+    pub key: String,
+    pub html: String,
+}
+
+/// An annotation as stored by Zotero, before it becomes a `HighlightJson`.
+#[derive(Debug)]
+struct Annotation {
+    id: String,
+    key: String,
+    text: Option<String>,
+    comment: Option<String>,
+    color: Option<String>,
+    sort_index: String,
+    // Dates as YYYY-MM-DD
+    date_added: String,
+    date_modified: Option<String>,
+}
+
+impl Annotation {
+    /// Whether the highlighted text has fewer words than `annotation_min_length`.
+    /// Sticky notes have no text and are always kept.
+    fn is_too_short(&self) -> bool {
+        match self.text.as_deref().map(str::trim) {
+            Some(text) if !text.is_empty() => {
+                text.split_whitespace().count() < SETTINGS.annotation_min_length
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the highlight for this annotation, or `None` if it has neither text nor comment.
+    fn into_highlight(self) -> Option<HighlightJson> {
+        let text = self.text.filter(|text| !text.trim().is_empty());
+        let comment = self.comment.filter(|comment| !comment.trim().is_empty());
+        let annotation_type = match (&text, &comment) {
+            (Some(_), _) => AnnotationType::Highlight,
+            (None, Some(_)) => AnnotationType::StandaloneNote,
+            (None, None) => return None,
+        };
+        let text = text.unwrap_or_default();
+        let highlight_original_length = text.chars().count();
+        let content = match SETTINGS.max_highlight_chars {
+            Some(max_chars) => truncate_at_sentence(&text, max_chars),
+            None => text,
+        };
+        Some(HighlightJson {
+            id: self.id,
+            annotation_key: self.key,
+            content,
+            highlight_original_length,
+            note: comment.unwrap_or_default(),
+            // The comment may have been edited after the annotation was created
+            note_saved_at: self.date_modified.unwrap_or(self.date_added.clone()),
+            possible_duplicate: false,
+            annotation_type,
+            color: self.color,
+            sort_index: self.sort_index,
+            date_added: self.date_added,
+        })
+    }
 }
 
 fn parse_date(date_str: &str) -> Option<DateTime<Utc>> {
+    // Imported items sometimes have dates with surrounding whitespace
+    let date_str = date_str.trim();
     if date_str.is_empty() {
         return None;
     }
@@ -57,21 +456,278 @@ fn parse_date(date_str: &str) -> Option<DateTime<Utc>> {
         return Some(Utc.from_utc_datetime(&naive_datetime));
     }
 
+    // Zotero stores dates with an unknown month or day as e.g. 2020-00-00,
+    // these are expected and not worth a warning
+    if !date_str.ends_with("-00") {
+        tracing::warn!("Could not parse date {:?}", date_str);
+    }
     None
 }
 
+/// Cuts `text` to at most `max_chars` characters, after the last sentence ending
+/// with `.`, `?` or `!` before the limit if there is one, and appends `[…]`.
+fn truncate_at_sentence(text: &str, max_chars: usize) -> String {
+    let Some((limit, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+    let head = &text[..limit];
+    let sentence_end = head
+        .char_indices()
+        .rev()
+        .find(|&(i, c)| {
+            matches!(c, '.' | '?' | '!')
+                && text[i + 1..].chars().next().is_none_or(char::is_whitespace)
+        })
+        .map_or(limit, |(i, _)| i + 1);
+    format!("{} […]", head[..sentence_end].trim_end())
+}
+
+/// Set by `--verbose-sql` to log SQL at DEBUG instead of TRACE level.
+static VERBOSE_SQL: AtomicBool = AtomicBool::new(false);
+
+/// Logs a query and its bound parameters under the `sql` tracing target.
+fn log_sql(query: &str, params: &[&dyn std::fmt::Debug]) {
+    if VERBOSE_SQL.load(Ordering::Relaxed) {
+        tracing::debug!(target: "sql", "SQL: {}\nparams: {:?}", query.trim(), params);
+    } else {
+        tracing::trace!(target: "sql", "SQL: {}\nparams: {:?}", query.trim(), params);
+    }
+}
+
+fn prepare_sql<'conn>(conn: &'conn Connection, query: &str) -> Result<Statement<'conn>> {
+    log_sql(query, &[]);
+    conn.prepare(query)
+}
+
+/// Removes the `url_strip_params` query parameters (e.g. `utm_source`) from a URL
+/// when `source_url_clean` is on. URLs that don't parse or have none of these
+/// parameters are returned unchanged.
+fn clean_source_url(source_url: &str) -> String {
+    if !SETTINGS.source_url_clean {
+        return source_url.to_string();
+    }
+    let Ok(mut url) = url::Url::parse(source_url) else {
+        return source_url.to_string();
+    };
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let kept: Vec<&(String, String)> = pairs
+        .iter()
+        .filter(|(name, _)| !SETTINGS.url_strip_params.contains(name))
+        .collect();
+    // Re-encoding the query could change the URL even with nothing removed
+    if kept.len() == pairs.len() {
+        return source_url.to_string();
+    }
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(kept.iter().map(|(name, value)| (name, value)));
+    }
+    url.into()
+}
+
+/// Removes the configured junk prefixes and suffixes from a title, ignoring case.
+fn clean_title(original: &str) -> String {
+    let mut title = original;
+    loop {
+        // Empty prefixes or suffixes would loop forever
+        let stripped_prefix = SETTINGS
+            .title_prefix_strip
+            .iter()
+            .filter(|prefix| !prefix.is_empty())
+            .find_map(|prefix| {
+                let head = title.get(..prefix.len())?;
+                (head.to_lowercase() == prefix.to_lowercase()).then(|| &title[prefix.len()..])
+            });
+        let stripped = stripped_prefix.or_else(|| {
+            SETTINGS
+                .title_suffix_strip
+                .iter()
+                .filter(|suffix| !suffix.is_empty())
+                .find_map(|suffix| {
+                    let split = title.len().checked_sub(suffix.len())?;
+                    let tail = title.get(split..)?;
+                    (tail.to_lowercase() == suffix.to_lowercase()).then(|| &title[..split])
+                })
+        });
+        match stripped {
+            Some(rest) => title = rest,
+            None => break,
+        }
+    }
+    // Keep titles that are nothing but junk as they are
+    match title.trim() {
+        "" => original.to_string(),
+        title => title.to_string(),
+    }
+}
+
+/// Changes the capitalization of a title according to `mode`.
+fn apply_title_case(title: &str, mode: TitleCaseMode) -> String {
+    match mode {
+        TitleCaseMode::Preserve => title.to_string(),
+        TitleCaseMode::TitleCase => titlecase::titlecase(title),
+        TitleCaseMode::SentenceCase => sentence_case(title),
+    }
+}
+
+/// Lowercases a title except for its first word, the first word after a colon,
+/// and words with capitals after their first letter, e.g. "DNA" or "LaTeX".
+/// Proper nouns can't be told apart from other capitalized words, so "Zotero"
+/// becomes "zotero". In titles written all in capitals, every word is lowercased.
+fn sentence_case(title: &str) -> String {
+    let all_caps = !title.chars().any(char::is_lowercase);
+    let mut capitalize_next = true;
+    let words: Vec<String> = title
+        .split(' ')
+        .map(|word| {
+            let keep_case = !all_caps && word.chars().skip(1).any(char::is_uppercase);
+            let word = if keep_case {
+                word.to_string()
+            } else {
+                word.to_lowercase()
+            };
+            let word = if capitalize_next && !word.is_empty() {
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            } else {
+                word
+            };
+            if !word.is_empty() {
+                capitalize_next = word.ends_with(':');
+            }
+            word
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// Parses Zotero's free-text "# of Pages" field, e.g. "248" or "xii, 248 p.",
+/// keeping the last number.
+fn parse_page_count(num_pages: &str) -> Option<u32> {
+    num_pages
+        .rsplit(|c: char| !c.is_ascii_digit())
+        .find(|part| !part.is_empty())?
+        .parse()
+        .ok()
+}
+
+/// Link to an item, opening it in the Zotero app or on zotero.org depending on `scheme`.
+/// Items of group libraries are identified by group ID, which differs from the library ID.
+fn zotero_item_url(item_key: &str, group_id: Option<i64>, scheme: &ZoteroUriScheme) -> String {
+    match (scheme, group_id) {
+        (ZoteroUriScheme::WebPersonal(_) | ZoteroUriScheme::WebGroup, Some(group_id)) => format!(
+            "https://www.zotero.org/groups/{}/items/{}",
+            group_id, item_key
+        ),
+        (ZoteroUriScheme::WebPersonal(user_id), None) => {
+            format!("https://zotero.org/users/{}/items/{}", user_id, item_key)
+        }
+        // Without a user ID, personal library items only have a desktop link
+        (ZoteroUriScheme::Desktop | ZoteroUriScheme::WebGroup, None) => {
+            format!("zotero://select/library/items/{}", item_key)
+        }
+        (ZoteroUriScheme::Desktop, Some(group_id)) => {
+            format!("zotero://select/groups/{}/items/{}", group_id, item_key)
+        }
+    }
+}
+
+/// Returns `count` comma-separated `?` placeholders, for `IN (...)` clauses.
+fn sql_placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+/// Replaces the `?` placeholders of `query` with `params` as SQL literals, so that
+/// the query can be run as is in another SQLite client. The queries have no `?`
+/// other than placeholders.
+fn interpolate_sql(query: &str, params: &[Value]) -> String {
+    let mut params = params.iter();
+    let mut interpolated = String::with_capacity(query.len());
+    for c in query.chars() {
+        if c == '?' {
+            if let Some(param) = params.next() {
+                interpolated.push_str(&sql_literal(param));
+                continue;
+            }
+        }
+        interpolated.push(c);
+    }
+    interpolated
+}
+
+fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(text) => format!("'{}'", text.replace('\'', "''")),
+        Value::Blob(blob) => format!(
+            "X'{}'",
+            blob.iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<String>()
+        ),
+    }
+}
+
+/// Prints the queries of papers and highlights with the settings' filters filled
+/// in, for `--print-sql`.
+fn print_sql() {
+    let (papers_query, papers_params) = papers_sql(None);
+    let (highlights_query, highlights_params) = highlights_sql(None);
+    println!("-- Papers");
+    println!("{};", interpolate_sql(papers_query.trim(), &papers_params));
+    println!();
+    println!("-- Highlights");
+    println!(
+        "{};",
+        interpolate_sql(highlights_query.trim(), &highlights_params)
+    );
+}
+
+// Columns are read by name so that reordering the query in `query_papers`
+// can't silently shift them
 fn map_row_to_paper(row: &Row) -> Result<Paper> {
-    let paper_id_int: i64 = row.get(0)?;
+    let paper_id_int: i64 = row.get("paperID")?;
     let paper_id = paper_id_int.to_string();
-    let title: String = row.get(1)?;
-    let url: Option<String> = row.get(2)?;
-    let date_added: String = row.get(3)?;
-    let zotero_uri: String = row.get(4)?;
-    let publication_date: Option<String> = row.get(5)?;
-    let authors: Option<String> = row.get(6)?;
+    let title = clean_title(&row.get::<_, String>("title")?);
+    let url: Option<String> = row.get("url")?;
+    let date_added: String = row.get("dateAdded")?;
+    let group_id: Option<i64> = row.get("group_id")?;
+    let publication_date: Option<String> = row.get("publication_date")?;
+    let item_type: String = row.get("item_type")?;
+    let attachment_db_path: Option<String> = row.get("attachment_path")?;
+    let attachment_key: Option<String> = row.get("attachment_key")?;
+    let note_count: i64 = row.get("note_count")?;
+    let short_title: Option<String> = row.get("short_title")?;
+    let attachment_content_type: Option<String> = row.get("attachment_content_type")?;
+    let series: Option<String> = row.get("series")?;
+    let series_number: Option<String> = row.get("series_number")?;
+    let pages: Option<String> = row.get("pages")?;
+    let page_count: Option<String> = row.get("page_count")?;
+    let access_date: Option<String> = row.get("access_date")?;
+    let item_key: String = row.get("item_key")?;
+    let volume_number: Option<String> = row.get("volume_number")?;
+    let number_of_volumes: Option<String> = row.get("number_of_volumes")?;
+    // PDFs are ignored like other attachments when content_type_filter leaves them out
+    let has_pdf = row.get::<_, bool>("has_pdf")?
+        && (SETTINGS.content_type_filter.is_empty()
+            || SETTINGS
+                .content_type_filter
+                .iter()
+                .any(|content_type| content_type == PDF_CONTENT_TYPE));
 
-    let has_url = url.is_some() && !url.as_ref().unwrap().is_empty();
-    let source_url = url.unwrap_or_default();
+    let has_url = url.as_deref().is_some_and(|url| !url.is_empty());
+    let source_url = clean_source_url(&url.unwrap_or_default());
 
     let roam_ref = if has_url {
         source_url.clone()
@@ -79,59 +735,183 @@ fn map_row_to_paper(row: &Row) -> Result<Paper> {
         format!("@zotero_{}", paper_id)
     };
 
-    let saved_at = parse_date(&date_added).unwrap_or_else(|| Utc::now());
+    let saved_at = parse_date(&date_added).unwrap_or_else(Utc::now);
     let published_date = publication_date.and_then(|date| parse_date(&date));
+    let date_accessed = access_date.and_then(|date| parse_date(&date));
+    let attachment_path = match (&attachment_db_path, &attachment_key) {
+        (Some(path), Some(key)) => get_attachment_path(path, key),
+        _ => None,
+    };
+    let attachment_filename = attachment_db_path.as_deref().and_then(attachment_filename);
+    let attachment_is_pdf = attachment_content_type.as_deref() == Some(PDF_CONTENT_TYPE);
 
     Ok(Paper {
         id: paper_id,
         has_url,
         roam_ref,
         source_url,
-        zotero_url: zotero_uri,
-        title,
-        author: authors.unwrap_or_default(),
+        zotero_url: zotero_item_url(&item_key, group_id, &SETTINGS.zotero_uri_scheme),
+        item_key,
+        title: apply_title_case(&title, SETTINGS.title_case),
+        title_original: title,
+        short_title: short_title
+            .filter(|short_title| !short_title.trim().is_empty())
+            .map(|short_title| clean_title(&short_title)),
+        series: series.filter(|series| !series.trim().is_empty()),
+        series_number: series_number.filter(|number| !number.trim().is_empty()),
+        pages: pages.filter(|pages| !pages.trim().is_empty()),
+        page_count: page_count.as_deref().and_then(parse_page_count),
+        volume_number: volume_number.filter(|volume| !volume.trim().is_empty()),
+        number_of_volumes: number_of_volumes.filter(|volumes| !volumes.trim().is_empty()),
+        // Filled in by query_papers once authors, tags and collections are queried
+        author: String::new(),
+        authors: Vec::new(),
+        editors: Vec::new(),
+        translators: Vec::new(),
+        item_type,
+        tags: Vec::new(),
+        tags_filtered: Vec::new(),
+        collection_path: None,
         saved_at,
+        publication_year: published_date.map(|date| date.year()),
         published_date,
+        date_accessed,
+        attachment_path,
+        attachment_content_type,
+        attachment_is_pdf,
+        has_pdf,
+        attachment_filename,
+        note_count: note_count as usize,
+        has_highlights: false,
+        highlight_count: 0,
+        // Filled in by set_related_papers
+        related: Vec::new(),
     })
 }
 
-fn query_papers(conn: &Connection) -> Result<Vec<Paper>> {
-    let query = r#"
-    SELECT DISTINCT
+const PDF_CONTENT_TYPE: &str = "application/pdf";
+
+/// Returns the file name from the `path` column of `itemAttachments`.
+fn attachment_filename(path: &str) -> Option<String> {
+    let path = path.strip_prefix("storage:").unwrap_or(path);
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Resolves the `path` column of `itemAttachments` to an existing file.
+/// Stored files look like `storage:<filename>` and live in `<storage_dir>/<key>/`,
+/// while linked files are stored with their full path.
+fn get_attachment_path(path: &str, attachment_key: &str) -> Option<PathBuf> {
+    let full_path = if let Some(filename) = path.strip_prefix("storage:") {
+        let storage_dir = match &SETTINGS.zotero_storage_dir {
+            Some(dir) => dir.clone(),
+            None => SETTINGS.zotero_db_path.parent()?.join("storage"),
+        };
+        storage_dir.join(attachment_key).join(filename)
+    } else if path.is_empty() {
+        return None;
+    } else {
+        PathBuf::from(path)
+    };
+
+    if full_path.is_file() {
+        Some(full_path)
+    } else {
+        tracing::debug!("Attachment file not found: {}", full_path.display());
+        None
+    }
+}
+
+/// Papers with at least one attachment, only those of `only_item_ids` when it is set.
+fn query_papers(conn: &Connection, only_item_ids: Option<&[i64]>) -> Result<Vec<Paper>> {
+    if only_item_ids.is_some_and(|ids| ids.is_empty()) {
+        return Ok(Vec::new());
+    }
+    let (query, params) = papers_sql(only_item_ids);
+    let mut stmt = prepare_sql(conn, &query)?;
+    let paper_iter = stmt.query_map(params_from_iter(&params), map_row_to_paper)?;
+
+    let mut creators_map = query_creators(conn)?;
+    let mut tags_map = query_tags(conn)?;
+    let mut collection_paths = query_collection_paths(conn)?;
+    let mut papers = Vec::new();
+    for paper_result in paper_iter {
+        let mut paper = paper_result?;
+        let creators = split_creators(
+            creators_map.remove(&paper.id).unwrap_or_default(),
+            &SETTINGS,
+        );
+        paper.authors = creators.authors;
+        paper.editors = creators.editors;
+        paper.translators = creators.translators;
+        paper.tags = tags_map.remove(&paper.id).unwrap_or_default();
+        paper.tags_filtered = filter_tags(&paper.tags, &SETTINGS);
+        paper.collection_path = collection_paths.remove(&paper.id);
+        paper.author = format_author_list(&paper.authors, &SETTINGS);
+        papers.push(paper);
+    }
+
+    Ok(papers)
+}
+
+/// The query of `query_papers` and its parameters, which `--print-sql` prints.
+fn papers_sql(only_item_ids: Option<&[i64]>) -> (String, Vec<Value>) {
+    // Attachments of other content types are ignored, as if the paper didn't have them
+    let content_type_filter = match SETTINGS.content_type_filter.len() {
+        0 => String::new(),
+        count => format!(
+            "AND attachments.contentType IN ({})",
+            sql_placeholders(count)
+        ),
+    };
+    let mut query = format!(
+        r#"
+    SELECT
         papers.itemID AS paperID,
         title_values.value AS title,
         url_values.value AS url,
         SUBSTR(papers.dateAdded, 1, 10) as dateAdded,
-        'zotero://select/items/' ||
-            CASE WHEN papers.libraryID = 1 THEN '0' ELSE papers.libraryID END ||
-            '_' || papers.key AS zotero_uri,
+        -- NULL for the personal library
+        groups.groupID AS group_id,
         SUBSTR(date_values.value, 1, 10) AS publication_date,
+        item_types.typeName AS item_type,
+        attachment.path AS attachment_path,
+        attachment_items.key AS attachment_key,
         (
-            SELECT GROUP_CONCAT(author_name, ', ')
-            FROM (
-                SELECT DISTINCT
-                    CASE
-                        WHEN c.fieldMode = 1 THEN c.lastName
-                        ELSE
-                            CASE
-                                WHEN c.firstName IS NOT NULL AND c.firstName != ''
-                                THEN c.firstName || ' ' || c.lastName
-                                ELSE c.lastName
-                            END
-                    END AS author_name,
-                    ic.orderIndex
-                FROM
-                    itemCreators ic
-                JOIN
-                    creators c ON ic.creatorID = c.creatorID
-                WHERE
-                    ic.itemID = papers.itemID
-                ORDER BY
-                    ic.orderIndex
-            )
-        ) AS authors
+            SELECT COUNT(*)
+            FROM itemNotes AS notes
+            JOIN items AS note_items ON notes.itemID = note_items.itemID
+            WHERE notes.parentItemID = papers.itemID
+                AND note_items.itemTypeID = (
+                    SELECT itemTypeID FROM itemTypes WHERE typeName = 'note'
+                )
+                AND notes.itemID NOT IN (SELECT itemID FROM deletedItems)
+        ) AS note_count,
+        short_title_values.value AS short_title,
+        attachment.contentType AS attachment_content_type,
+        series_values.value AS series,
+        series_number_values.value AS series_number,
+        pages_values.value AS pages,
+        page_count_values.value AS page_count,
+        access_date_values.value AS access_date,
+        papers.key AS item_key,
+        volume_values.value AS volume_number,
+        volumes_values.value AS number_of_volumes,
+        EXISTS (
+            SELECT 1
+            FROM itemAttachments AS attachments
+            WHERE attachments.parentItemID = papers.itemID
+                AND attachments.contentType = 'application/pdf'
+                AND attachments.path IS NOT NULL
+                AND attachments.itemID NOT IN (SELECT itemID FROM deletedItems)
+        ) AS has_pdf
     FROM
         items AS papers
+    LEFT JOIN
+        groups ON papers.libraryID = groups.libraryID
+    JOIN
+        itemTypes AS item_types ON papers.itemTypeID = item_types.itemTypeID
     JOIN
         itemData AS title_data ON papers.itemID = title_data.itemID AND title_data.fieldID = 1
     JOIN
@@ -140,94 +920,763 @@ fn query_papers(conn: &Connection) -> Result<Vec<Paper>> {
         itemData AS url_data ON papers.itemID = url_data.itemID AND url_data.fieldID = 13
     LEFT JOIN
         itemDataValues AS url_values ON url_data.valueID = url_values.valueID
+    LEFT JOIN
+        itemData AS short_title_data ON papers.itemID = short_title_data.itemID AND short_title_data.fieldID = 3
+    LEFT JOIN
+        itemDataValues AS short_title_values ON short_title_data.valueID = short_title_values.valueID
+    LEFT JOIN
+        itemData AS series_data ON papers.itemID = series_data.itemID AND series_data.fieldID = 51
+    LEFT JOIN
+        itemDataValues AS series_values ON series_data.valueID = series_values.valueID
+    LEFT JOIN
+        itemData AS series_number_data ON papers.itemID = series_number_data.itemID AND series_number_data.fieldID = 52
+    LEFT JOIN
+        itemDataValues AS series_number_values ON series_number_data.valueID = series_number_values.valueID
+    LEFT JOIN
+        itemData AS pages_data ON papers.itemID = pages_data.itemID AND pages_data.fieldID = 32
+    LEFT JOIN
+        itemDataValues AS pages_values ON pages_data.valueID = pages_values.valueID
+    LEFT JOIN
+        itemData AS page_count_data ON papers.itemID = page_count_data.itemID AND page_count_data.fieldID = 48
+    LEFT JOIN
+        itemDataValues AS page_count_values ON page_count_data.valueID = page_count_values.valueID
+    LEFT JOIN
+        itemData AS access_date_data ON papers.itemID = access_date_data.itemID AND access_date_data.fieldID = 90
+    LEFT JOIN
+        itemDataValues AS access_date_values ON access_date_data.valueID = access_date_values.valueID
+    LEFT JOIN
+        itemData AS volume_data ON papers.itemID = volume_data.itemID AND volume_data.fieldID = 10
+    LEFT JOIN
+        itemDataValues AS volume_values ON volume_data.valueID = volume_values.valueID
+    LEFT JOIN
+        itemData AS volumes_data ON papers.itemID = volumes_data.itemID AND volumes_data.fieldID = 56
+    LEFT JOIN
+        itemDataValues AS volumes_values ON volumes_data.valueID = volumes_values.valueID
     LEFT JOIN
         itemData AS date_data ON papers.itemID = date_data.itemID AND date_data.fieldID = 6
     LEFT JOIN
         itemDataValues AS date_values ON date_data.valueID = date_values.valueID
-    JOIN
-        itemAttachments AS attachments ON papers.itemID = attachments.parentItemID
-    GROUP BY
-        papers.itemID, title_values.value, url_values.value, papers.libraryID, papers.key, date_values.value
-    "#;
+    LEFT JOIN
+        itemAttachments AS attachment ON attachment.itemID = (
+            -- The first PDF, or the first other file (EPUB, HTML snapshot...)
+            SELECT attachments.itemID
+            FROM itemAttachments AS attachments
+            WHERE attachments.parentItemID = papers.itemID
+                AND attachments.path IS NOT NULL
+                AND attachments.itemID NOT IN (SELECT itemID FROM deletedItems)
+                {}
+            ORDER BY
+                attachments.contentType = 'application/pdf' DESC,
+                attachments.itemID
+            LIMIT 1
+        )
+    LEFT JOIN
+        items AS attachment_items ON attachment.itemID = attachment_items.itemID
+    WHERE
+        -- EXISTS rather than a JOIN so that papers with several attachments
+        -- are only returned once
+        EXISTS (
+            SELECT 1
+            FROM itemAttachments AS attachments
+            WHERE attachments.parentItemID = papers.itemID
+                AND attachments.itemID NOT IN (SELECT itemID FROM deletedItems)
+                {}
+        )
+        -- Items in Zotero's trash are listed in deletedItems until it is emptied
+        AND papers.itemID NOT IN (SELECT itemID FROM deletedItems)
+    "#,
+        content_type_filter, content_type_filter
+    );
 
-    let mut stmt = conn.prepare(query)?;
-    let paper_iter = stmt.query_map([], |row| map_row_to_paper(row))?;
+    // Once for each use of the filter above
+    let mut params: Vec<Value> = Vec::new();
+    for _ in 0..2 {
+        params.extend(
+            SETTINGS
+                .content_type_filter
+                .iter()
+                .map(|content_type| Value::Text(content_type.clone())),
+        );
+    }
+    // Items in either blocklist are skipped
+    if !SETTINGS.skip_item_ids.is_empty() {
+        query.push_str(&format!(
+            "    AND papers.itemID NOT IN ({})\n",
+            sql_placeholders(SETTINGS.skip_item_ids.len())
+        ));
+        params.extend(SETTINGS.skip_item_ids.iter().map(|id| Value::Integer(*id)));
+    }
+    if !SETTINGS.skip_zotero_keys.is_empty() {
+        query.push_str(&format!(
+            "    AND papers.key NOT IN ({})\n",
+            sql_placeholders(SETTINGS.skip_zotero_keys.len())
+        ));
+        params.extend(
+            SETTINGS
+                .skip_zotero_keys
+                .iter()
+                .map(|key| Value::Text(key.clone())),
+        );
+    }
 
-    let mut papers = Vec::new();
-    for paper_result in paper_iter {
-        papers.push(paper_result?);
+    if let Some(only_item_ids) = only_item_ids {
+        query.push_str(&format!(
+            "    AND papers.itemID IN ({})\n",
+            sql_placeholders(only_item_ids.len())
+        ));
+        params.extend(only_item_ids.iter().map(|id| Value::Integer(*id)));
     }
 
-    Ok(papers)
+    (query, params)
 }
 
-fn query_highlights(conn: &Connection) -> Result<HashMap<String, Vec<HighlightJson>>> {
-    let query = r#"
+/// Returns the collection path of each item in a collection, e.g. "Research/ML".
+/// Items in several collections get the first path alphabetically.
+fn query_collection_paths(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = prepare_sql(
+        conn,
+        "SELECT collectionID, collectionName, parentCollectionID FROM collections",
+    )?;
+    let collections: HashMap<i64, (String, Option<i64>)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+        .collect::<Result<_>>()?;
+
+    let mut stmt = prepare_sql(conn, "SELECT itemID, collectionID FROM collectionItems")?;
+    let mut rows = stmt.query([])?;
+    let mut paths: HashMap<String, String> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let item_id: i64 = row.get(0)?;
+        let collection_id: i64 = row.get(1)?;
+        let path = collection_path(collection_id, |id| {
+            collections
+                .get(&id)
+                .map(|(name, parent)| (name.as_str(), *parent))
+        });
+        let entry = paths
+            .entry(item_id.to_string())
+            .or_insert_with(|| path.clone());
+        if path < *entry {
+            *entry = path;
+        }
+    }
+    Ok(paths)
+}
+
+/// Joins the names of a collection and its ancestors into a path such as
+/// "Research/ML", given the name and parent of each collection.
+fn collection_path<'a, K: Copy>(
+    collection: K,
+    name_and_parent: impl Fn(K) -> Option<(&'a str, Option<K>)>,
+) -> String {
+    let mut names = Vec::new();
+    let mut current = Some(collection);
+    while let Some((name, parent)) = current.and_then(&name_and_parent) {
+        // Names become directory names, so they can't contain separators
+        names.push(name.replace(['/', '\\'], "-"));
+        current = parent;
+    }
+    names.reverse();
+    names.join("/")
+}
+
+fn table_has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = prepare_sql(conn, &format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Joins the names of the distinct creators with `authors_separator`, using
+/// `authors_last_separator` before the last one when it is set.
+fn format_author_list(authors: &[Author], settings: &Settings) -> String {
+    let mut seen = Vec::new();
+    let mut names: Vec<&str> = Vec::new();
+    for author in authors {
+        // The same creator can be listed once per role, e.g. author and editor,
+        // while different creators can share a name
+        let key = (
+            author.creator_id,
+            author.first_name.as_str(),
+            author.last_name.as_str(),
+            author.orcid.as_deref(),
+        );
+        if !seen.contains(&key) {
+            seen.push(key);
+            names.push(&author.name);
+        }
+    }
+    match (names.split_last(), &settings.authors_last_separator) {
+        (Some((last, rest)), Some(last_separator)) if !rest.is_empty() => format!(
+            "{}{}{}",
+            rest.join(&settings.authors_separator),
+            last_separator,
+            last
+        ),
+        _ => names.join(&settings.authors_separator),
+    }
+}
+
+/// Returns the creators of each item in order, with their creator type.
+fn query_creators(conn: &Connection) -> Result<HashMap<String, Vec<(String, Author)>>> {
+    // Zotero has no settled place for ORCIDs yet: read them when the creators
+    // table has an `orcid` column, and fall back to NULL otherwise.
+    let orcid_column = if table_has_column(conn, "creators", "orcid")? {
+        "c.orcid"
+    } else {
+        "NULL"
+    };
+    // Some recent Zotero versions store a single `fullName` for some creators
+    let full_name_column = if table_has_column(conn, "creators", "fullName")? {
+        "c.fullName"
+    } else {
+        "NULL"
+    };
+    let query = format!(
+        r#"
     SELECT
-        annotations.itemID AS annotationID,
-        annotations.text AS highlight_text,
-        annotations.comment AS highlight_comment,
-        attachments.parentItemID AS paperID,
-        SUBSTR(items.dateAdded, 1, 10) AS date_added
+        ic.itemID AS paperID,
+        c.creatorID AS creator_id,
+        c.firstName AS first_name,
+        c.lastName AS last_name,
+        c.fieldMode AS field_mode,
+        {} AS orcid,
+        {} AS full_name,
+        ct.creatorType AS creator_type
     FROM
-        itemAnnotations AS annotations
+        itemCreators ic
     JOIN
-        itemAttachments AS attachments ON annotations.parentItemID = attachments.itemID
-    JOIN
-        items ON annotations.itemID = items.itemID
+        creators c ON ic.creatorID = c.creatorID
+    LEFT JOIN
+        creatorTypes ct ON ic.creatorTypeID = ct.creatorTypeID
     ORDER BY
-        attachments.parentItemID,
-        CAST(SUBSTR(annotations.sortIndex, 1, 5) AS INTEGER),
-        CAST(SUBSTR(annotations.sortIndex, 7, 6) AS INTEGER),
-        CAST(SUBSTR(annotations.sortIndex, 14) AS INTEGER)
-    "#;
+        ic.itemID,
+        ic.orderIndex
+    "#,
+        orcid_column, full_name_column
+    );
 
-    let mut stmt = conn.prepare(query)?;
+    let mut stmt = prepare_sql(conn, &query)?;
     let mut rows = stmt.query([])?;
 
-    let mut highlights_map: HashMap<String, Vec<HighlightJson>> = HashMap::new();
+    let mut creators_map: HashMap<String, Vec<(String, Author)>> = HashMap::new();
 
     while let Some(row) = rows.next()? {
-        let annotation_id_int: i64 = row.get(0)?;
-        let annotation_id = annotation_id_int.to_string();
-        let highlight_text: Option<String> = row.get(1)?;
-        let highlight_comment: Option<String> = row.get(2)?;
-        let paper_id_int: i64 = row.get(3)?;
-        let paper_id = paper_id_int.to_string();
-        let date_added: String = row.get(4)?;
-
-        if highlight_text.is_none() || highlight_text.as_ref().unwrap().trim().is_empty() {
-            continue;
-        }
+        let paper_id_int: i64 = row.get(0)?;
+        let creator_id: i64 = row.get(1)?;
+        let first_name: Option<String> = row.get(2)?;
+        let last_name: Option<String> = row.get(3)?;
+        let field_mode: Option<i64> = row.get(4)?;
+        let orcid: Option<String> = row.get::<_, Option<String>>(5).unwrap_or(None);
+        let full_name: Option<String> = row.get(6)?;
+        let creator_type: Option<String> = row.get(7)?;
 
-        let highlight_json = HighlightJson {
-            id: annotation_id,
-            content: highlight_text.unwrap_or_default(),
-            note: highlight_comment.unwrap_or_default(),
-            note_saved_at: date_added,
+        let first_name = first_name.unwrap_or_default();
+        let last_name = last_name.unwrap_or_default();
+        let name = match full_name.filter(|full_name| !full_name.trim().is_empty()) {
+            Some(full_name) => {
+                tracing::debug!(
+                    "Using the full name of a creator of item {}: {}",
+                    paper_id_int,
+                    full_name
+                );
+                full_name
+            }
+            None if field_mode == Some(1) || first_name.is_empty() => last_name.clone(),
+            None => format!("{} {}", first_name, last_name),
         };
 
-        highlights_map
-            .entry(paper_id)
-            .or_insert_with(Vec::new)
-            .push(highlight_json);
+        creators_map
+            .entry(paper_id_int.to_string())
+            .or_default()
+            .push((
+                creator_type.unwrap_or_else(|| "author".to_string()),
+                Author {
+                    first_name,
+                    last_name,
+                    name,
+                    orcid: orcid.filter(|o| !o.trim().is_empty()),
+                    creator_id: Some(creator_id),
+                },
+            ));
     }
 
-    Ok(highlights_map)
+    Ok(creators_map)
 }
 
+/// Returns the tags of each item, sorted by name.
+fn query_tags(conn: &Connection) -> Result<HashMap<String, Vec<String>>> {
+    let query = r#"
+    SELECT
+        it.itemID,
+        t.name
+    FROM
+        itemTags it
+    JOIN
+        tags t ON it.tagID = t.tagID
+    ORDER BY
+        it.itemID,
+        t.name
+    "#;
+    let mut stmt = prepare_sql(conn, query)?;
+    let mut rows = stmt.query([])?;
+
+    let mut tags_map: HashMap<String, Vec<String>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let item_id: i64 = row.get(0)?;
+        tags_map
+            .entry(item_id.to_string())
+            .or_default()
+            .push(row.get(1)?);
+    }
+    Ok(tags_map)
+}
+
+/// Drops the tags starting with `_`, used internally by Zotero plugins (e.g.
+/// `_tablet`), unless `exclude_internal_tags` is off, and those starting with
+/// one of `tag_prefix_exclude`. The others are renamed with `tag_mapping` and,
+/// with `tag_sanitize`, made valid org tags; duplicates and tags left empty
+/// are dropped.
+pub fn filter_tags(tags: &[String], settings: &Settings) -> Vec<String> {
+    let mut filtered: Vec<String> = Vec::new();
+    for tag in tags
+        .iter()
+        .filter(|tag| !(settings.exclude_internal_tags && tag.starts_with('_')))
+        .filter(|tag| {
+            !settings
+                .tag_prefix_exclude
+                .iter()
+                .any(|prefix| tag.starts_with(prefix.as_str()))
+        })
+    {
+        let tag = settings.tag_mapping.get(tag).unwrap_or(tag);
+        let tag = if settings.tag_sanitize {
+            sanitize_tag(tag)
+        } else {
+            tag.clone()
+        };
+        if !tag.is_empty() && !filtered.contains(&tag) {
+            filtered.push(tag);
+        }
+    }
+    filtered
+}
+
+/// Makes `tag` a valid org tag, which only has letters, digits and `_@#%`:
+/// whitespace becomes `_` and other characters are removed, e.g. `"deep
+/// learning"` becomes `deep_learning` and `"self-supervised"` `selfsupervised`.
+fn sanitize_tag(tag: &str) -> String {
+    tag.split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '_' | '@' | '#' | '%'))
+        .collect()
+}
+
+/// Returns the Zotero userdata schema version, read from Zotero's `version`
+/// table, or from `PRAGMA user_version` when that table is missing.
+fn detect_zotero_schema_version(conn: &Connection) -> Result<u32> {
+    let has_version_table: bool = prepare_sql(
+        conn,
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'version')",
+    )?
+    .query_row([], |row| row.get(0))?;
+    if has_version_table {
+        let userdata_version: Option<u32> = prepare_sql(
+            conn,
+            "SELECT version FROM version WHERE schema = 'userdata'",
+        )?
+        .query_row([], |row| row.get(0))
+        .optional()?;
+        if let Some(version) = userdata_version {
+            return Ok(version);
+        }
+    }
+    prepare_sql(conn, "PRAGMA user_version")?.query_row([], |row| row.get(0))
+}
+
+/// Highlights of a single paper, for `stream_highlights`.
+fn query_highlights_for_paper(conn: &Connection, paper_id: i64) -> Result<Vec<HighlightJson>> {
+    Ok(query_highlights(conn, Some(paper_id))?
+        .remove(&paper_id.to_string())
+        .unwrap_or_default())
+}
+
+/// Highlights by paper id, of all papers, or only of `paper_id` when it is set.
+fn query_highlights(
+    conn: &Connection,
+    paper_id: Option<i64>,
+) -> Result<HashMap<String, Vec<HighlightJson>>> {
+    let (query, params) = highlights_sql(paper_id);
+    let mut stmt = prepare_sql(conn, &query)?;
+    let mut rows = stmt.query(params_from_iter(&params))?;
+
+    let mut highlights_map: HashMap<String, Vec<HighlightJson>> = HashMap::new();
+    let mut skipped_too_short = 0;
+
+    while let Some(row) = rows.next()? {
+        let annotation_id_int: i64 = row.get(0)?;
+        let paper_id_int: i64 = row.get(3)?;
+        let paper_id = paper_id_int.to_string();
+        let annotation = Annotation {
+            id: annotation_id_int.to_string(),
+            key: row.get(6)?,
+            text: row.get(1)?,
+            comment: row.get(2)?,
+            color: row.get(7)?,
+            sort_index: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+            date_added: row.get(4)?,
+            date_modified: row.get(5)?,
+        };
+        if annotation.is_too_short() {
+            skipped_too_short += 1;
+            continue;
+        }
+
+        let Some(highlight_json) = annotation.into_highlight() else {
+            continue;
+        };
+
+        highlights_map
+            .entry(paper_id)
+            .or_default()
+            .push(highlight_json);
+    }
+    if skipped_too_short > 0 {
+        tracing::debug!(
+            "Skipped {} highlights shorter than annotation_min_length",
+            skipped_too_short
+        );
+    }
+
+    Ok(highlights_map)
+}
+
+/// The query of `query_highlights` and its parameters, which `--print-sql`
+/// prints.
+fn highlights_sql(paper_id: Option<i64>) -> (String, Vec<Value>) {
+    let paper_filter = match paper_id {
+        Some(_) => "AND attachments.parentItemID = ?",
+        None => "",
+    };
+    let query = format!(
+        r#"
+    SELECT
+        annotations.itemID AS annotationID,
+        annotations.text AS highlight_text,
+        annotations.comment AS highlight_comment,
+        attachments.parentItemID AS paperID,
+        SUBSTR(items.dateAdded, 1, 10) AS date_added,
+        SUBSTR(items.dateModified, 1, 10) AS date_modified,
+        items.key AS annotation_key,
+        annotations.color AS color,
+        annotations.sortIndex AS sort_index
+    FROM
+        itemAnnotations AS annotations
+    JOIN
+        itemAttachments AS attachments ON annotations.parentItemID = attachments.itemID
+    JOIN
+        items ON annotations.itemID = items.itemID
+    WHERE
+        annotations.itemID NOT IN (SELECT itemID FROM deletedItems)
+        {}
+    ORDER BY
+        attachments.parentItemID,
+        CAST(SUBSTR(annotations.sortIndex, 1, 5) AS INTEGER),
+        CAST(SUBSTR(annotations.sortIndex, 7, 6) AS INTEGER),
+        CAST(SUBSTR(annotations.sortIndex, 14) AS INTEGER)
+    "#,
+        paper_filter
+    );
+
+    (query, paper_id.into_iter().map(Value::Integer).collect())
+}
+
+/// Child notes of a paper, in the order they were added, for `include_child_notes`.
+fn query_child_notes_for_paper(conn: &Connection, paper_id: i64) -> Result<Vec<NoteContent>> {
+    let query = r#"
+    SELECT
+        note_items.key AS note_key,
+        notes.note AS note
+    FROM
+        itemNotes AS notes
+    JOIN
+        items AS note_items ON notes.itemID = note_items.itemID
+    WHERE
+        notes.parentItemID = ?1
+        AND note_items.itemTypeID = (
+            SELECT itemTypeID FROM itemTypes WHERE typeName = 'note'
+        )
+        AND notes.itemID NOT IN (SELECT itemID FROM deletedItems)
+    ORDER BY
+        note_items.dateAdded,
+        note_items.itemID
+    "#;
+    let mut stmt = prepare_sql(conn, query)?;
+    let notes = stmt.query_map([paper_id], |row| {
+        Ok(NoteContent {
+            key: row.get("note_key")?,
+            html: row.get::<_, Option<String>>("note")?.unwrap_or_default(),
+        })
+    })?;
+    notes.collect()
+}
+
+/// Child notes converted to org, without those that are empty once converted.
+fn child_notes_org(notes: &[NoteContent]) -> Vec<String> {
+    notes
+        .iter()
+        .filter_map(|note| {
+            let org = html::html_to_org(&note.html);
+            if org.is_empty() {
+                tracing::debug!("Skipping empty note {}", note.key);
+                return None;
+            }
+            Some(org)
+        })
+        .collect()
+}
+
+/// Returns the IDs of the items modified after `since`, and of the papers whose
+/// attachments or annotations were, so that new highlights are caught too.
+/// Deleted annotations leave no trace and are not caught.
+fn query_items_modified_since(conn: &Connection, since: DateTime<Utc>) -> Result<Vec<i64>> {
+    let query = r#"
+    SELECT itemID
+    FROM items
+    WHERE dateModified > ?1
+    UNION
+    SELECT attachments.parentItemID
+    FROM items
+    JOIN itemAttachments AS attachments ON items.itemID = attachments.itemID
+    WHERE items.dateModified > ?1
+        AND attachments.parentItemID IS NOT NULL
+    UNION
+    SELECT attachments.parentItemID
+    FROM items
+    JOIN itemAnnotations AS annotations ON items.itemID = annotations.itemID
+    JOIN itemAttachments AS attachments ON annotations.parentItemID = attachments.itemID
+    WHERE items.dateModified > ?1
+        AND attachments.parentItemID IS NOT NULL
+    "#;
+    // Zotero stores modification dates in UTC, as YYYY-MM-DD HH:MM:SS
+    let since = since.format("%Y-%m-%d %H:%M:%S").to_string();
+    log_sql(query, &[&since]);
+    let mut stmt = conn.prepare(query)?;
+    let item_ids = stmt.query_map([&since], |row| row.get(0))?;
+    item_ids.collect()
+}
+
+/// Returns the IDs of the items related to each of `paper_ids`.
+///
+/// Zotero stores relations in `itemRelations` as `dc:relation` predicates whose
+/// object is the URI of the other item, e.g. `http://zotero.org/users/local/abcd1234/items/ABCD2345`,
+/// recorded on both items.
+fn query_related_items(
+    conn: &Connection,
+    paper_ids: &[String],
+) -> Result<HashMap<String, Vec<String>>> {
+    if paper_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let query = format!(
+        r#"
+    SELECT
+        relations.itemID,
+        related.itemID
+    FROM
+        itemRelations AS relations
+    JOIN
+        relationPredicates AS predicates ON relations.predicateID = predicates.predicateID
+    JOIN
+        items AS related ON relations.object LIKE '%/items/' || related.key
+    WHERE
+        predicates.predicate = 'dc:relation'
+        AND relations.itemID IN ({})
+    ORDER BY
+        relations.itemID,
+        related.itemID
+    "#,
+        sql_placeholders(paper_ids.len())
+    );
+    let params: Vec<&dyn ToSql> = paper_ids.iter().map(|id| id as &dyn ToSql).collect();
+    let mut stmt = prepare_sql(conn, &query)?;
+    let mut rows = stmt.query(params.as_slice())?;
+
+    let mut related_map: HashMap<String, Vec<String>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let paper_id: i64 = row.get(0)?;
+        let related_id: i64 = row.get(1)?;
+        related_map
+            .entry(paper_id.to_string())
+            .or_default()
+            .push(related_id.to_string());
+    }
+    Ok(related_map)
+}
+
+/// Fills in `Paper::related` with the related items that are papers themselves.
+fn set_related_papers(papers: &mut [Paper], related_map: &HashMap<String, Vec<String>>) {
+    let papers_by_id: HashMap<String, RelatedPaper> = papers
+        .iter()
+        .map(|paper| {
+            (
+                paper.id.clone(),
+                RelatedPaper {
+                    roam_ref: paper.roam_ref.clone(),
+                    title: paper.title.clone(),
+                },
+            )
+        })
+        .collect();
+    for paper in papers {
+        paper.related = related_map
+            .get(&paper.id)
+            .into_iter()
+            .flatten()
+            .filter_map(|related_id| papers_by_id.get(related_id).cloned())
+            .collect();
+    }
+}
+
+/// The annotations counted as highlights: those with neither text nor comment are
+/// skipped, as in `Annotation::into_highlight`.
+const COUNTED_ANNOTATIONS: &str = r#"
+        annotations.itemID NOT IN (SELECT itemID FROM deletedItems)
+        AND (
+            TRIM(COALESCE(annotations.text, ''), ' ' || CHAR(9, 10, 13)) != ''
+            OR TRIM(COALESCE(annotations.comment, ''), ' ' || CHAR(9, 10, 13)) != ''
+        )"#;
+
+/// Number of highlights of each paper that has any, without loading them.
+fn query_highlight_counts(conn: &Connection) -> Result<HashMap<String, usize>> {
+    let query = format!(
+        r#"
+    SELECT
+        attachments.parentItemID AS paperID,
+        COUNT(*) AS highlight_count
+    FROM
+        itemAnnotations AS annotations
+    JOIN
+        itemAttachments AS attachments ON annotations.parentItemID = attachments.itemID
+    WHERE{}
+    GROUP BY
+        attachments.parentItemID
+    "#,
+        COUNTED_ANNOTATIONS
+    );
+    let mut stmt = prepare_sql(conn, &query)?;
+    let mut rows = stmt.query([])?;
+
+    let mut counts = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let paper_id: i64 = row.get(0)?;
+        let count: i64 = row.get(1)?;
+        counts.insert(paper_id.to_string(), count as usize);
+    }
+    Ok(counts)
+}
+
+/// Number of highlights on the attachments of a single paper, without loading them.
+fn query_attachment_annotations_count(conn: &Connection, paper_id: i64) -> Result<usize> {
+    let query = format!(
+        r#"
+    SELECT
+        COUNT(*)
+    FROM
+        itemAnnotations AS annotations
+    JOIN
+        itemAttachments AS attachments ON annotations.parentItemID = attachments.itemID
+    WHERE
+        attachments.parentItemID = ?1
+        AND{}
+    "#,
+        COUNTED_ANNOTATIONS
+    );
+    let count: i64 = prepare_sql(conn, &query)?.query_row([paper_id], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+fn set_highlight_counts(papers: &mut [Paper], highlight_counts: &HashMap<String, usize>) {
+    for paper in papers {
+        paper.highlight_count = highlight_counts.get(&paper.id).copied().unwrap_or(0);
+        paper.has_highlights = paper.highlight_count > 0;
+    }
+}
+
+/// The keyword holding the ref of org-roam v1 files.
+const ROAM_KEY_KEYWORD: &str = "#+ROAM_KEY:";
+
+/// `text` without `prefix`, ignoring ASCII case as org does for keywords and
+/// property names.
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &text[prefix.len()..])
+}
+
+/// The ref set on `line` by `property` (e.g. `:ROAM_REFS:`) or `#+ROAM_KEY:`.
+fn line_roam_ref<'a>(line: &'a str, property: &str) -> Option<&'a str> {
+    [property, ROAM_KEY_KEYWORD]
+        .iter()
+        .find_map(|marker| strip_prefix_ignore_case(line, marker))
+        .map(str::trim)
+        .filter(|roam_ref| !roam_ref.is_empty())
+}
+
+/// How often `get_existing_refs` reports progress while scanning.
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns the files of `org_roam_dir` by `:ROAM_REFS:` value, or the property
+/// of the `org_roam_ref_property` setting, or by the `#+ROAM_KEY:` of org-roam
+/// v1 files, whatever `org_roam_version` is. `on_progress` is
+/// called with the number of refs found so far when the scan takes a while.
 fn get_existing_refs(
     org_roam_dir: &Path,
+    mut on_progress: impl FnMut(usize),
 ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let output = Command::new("rg")
+    if let Some(org_roam_db_path) = &SETTINGS.org_roam_db_path {
+        return Ok(query_org_roam_refs(org_roam_db_path)?);
+    }
+
+    let property = format!(":{}:", SETTINGS.org_roam_ref_property);
+    let mut child = Command::new("rg")
         .args([
             "--with-filename",
             "--fixed-strings",
-            ":ROAM_REFS:",
+            "--ignore-case",
+            "-e",
+            &property,
+            "-e",
+            ROAM_KEY_KEYWORD,
             &org_roam_dir.to_string_lossy(),
         ])
-        .output()?;
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    let mut refs_map = HashMap::new();
+    let mut last_progress = Instant::now();
+    for (index, line) in BufReader::new(stdout).lines().enumerate() {
+        let line = line?;
+        if let Some((filename, rest)) = line.split_once(":") {
+            if let Some(roam_ref) = line_roam_ref(rest, &property) {
+                refs_map.insert(roam_ref.to_string(), filename.to_string());
+            }
+        }
+        if index.is_multiple_of(100) && last_progress.elapsed() >= SCAN_PROGRESS_INTERVAL {
+            on_progress(refs_map.len());
+            last_progress = Instant::now();
+        }
+    }
 
+    let output = child.wait_with_output()?;
     if !output.status.success() {
         eprintln!(
             "ripgrep command failed: {}",
@@ -235,24 +1684,88 @@ fn get_existing_refs(
         );
         return Ok(HashMap::new());
     }
+    Ok(refs_map)
+}
+
+/// Reads the refs of all nodes from org-roam's database, by the value they have
+/// in `:ROAM_REFS:`.
+fn query_org_roam_refs(org_roam_db_path: &Path) -> Result<HashMap<String, String>> {
+    let conn =
+        Connection::open_with_flags(org_roam_db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let query = r#"
+    SELECT
+        refs.type,
+        refs.ref,
+        nodes.file
+    FROM
+        refs
+    JOIN
+        nodes ON nodes.id = refs.node_id
+    "#;
+    let mut stmt = prepare_sql(&conn, query)?;
+    let mut rows = stmt.query([])?;
 
-    let output_str = String::from_utf8(output.stdout)?;
     let mut refs_map = HashMap::new();
-    for line in output_str.lines() {
-        if let Some((filename, rest)) = line.split_once(":") {
-            if let Some(roam_ref) = rest.strip_prefix(":ROAM_REFS:") {
-                let trimmed_ref = roam_ref.trim().to_string();
-                if !trimmed_ref.is_empty() {
-                    refs_map.insert(trimmed_ref, filename.to_string());
-                }
-            }
-        }
+    while let Some(row) = rows.next()? {
+        // emacsql stores strings as quoted elisp strings
+        let ref_type = unquote_elisp_string(&row.get::<_, String>(0)?);
+        let roam_ref = unquote_elisp_string(&row.get::<_, String>(1)?);
+        let file = unquote_elisp_string(&row.get::<_, String>(2)?);
+        // org-roam splits "https://example.com" into the type "https" and the
+        // ref "//example.com", and "@key" into the type "cite" and the ref "key"
+        let full_ref = if ref_type == "cite" {
+            format!("@{}", roam_ref)
+        } else {
+            format!("{}:{}", ref_type, roam_ref)
+        };
+        refs_map.insert(full_ref, file);
     }
     Ok(refs_map)
 }
 
-fn get_new_entry_filename(org_roam_dir: &Path, title: &str, url: Option<&str>) -> String {
-    let now = Local::now();
+fn unquote_elisp_string(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(inner) => inner.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+/// Formats a count with thousands separators, e.g. 1,234.
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+fn scan_progress_message(refs_found: usize) -> String {
+    format!(
+        "Still scanning org-roam dir, found {} refs so far...",
+        format_count(refs_found)
+    )
+}
+
+/// The path of a new file, `<prefix>-<title slug>.org`, where the prefix is
+/// `author_prefix` or the current time, and with a hash of `url` before the
+/// extension when it is set, to tell apart papers with the same title.
+fn get_new_entry_filename(
+    org_roam_dir: &Path,
+    title: &str,
+    url: Option<&str>,
+    author_prefix: Option<&str>,
+) -> String {
+    let prefix = match author_prefix {
+        Some(author_prefix) => author_prefix.to_string(),
+        None => Local::now().format("%Y%m%d%H%M%S").to_string(),
+    };
     let slug = slug::slugify(title);
     let truncated_slug = if slug.len() > 100 {
         slug[..100].to_string()
@@ -276,9 +1789,7 @@ fn get_new_entry_filename(org_roam_dir: &Path, title: &str, url: Option<&str>) -
     org_roam_dir
         .join(format!(
             "{}-{}{}.org",
-            now.format("%Y%m%d%H%M%S"),
-            truncated_slug,
-            maybe_url_part
+            prefix, truncated_slug, maybe_url_part
         ))
         .to_string_lossy()
         .into_owned()
@@ -287,7 +1798,10 @@ fn get_new_entry_filename(org_roam_dir: &Path, title: &str, url: Option<&str>) -
 fn get_duplicate_titles(documents: &[Paper]) -> Vec<String> {
     let mut title_counts: HashMap<String, u32> = HashMap::new();
     for document in documents {
-        *title_counts.entry(document.title.clone()).or_default() += 1;
+        // Titles that end up in file names, so that their slugs get disambiguated
+        *title_counts
+            .entry(document.filename_title().to_string())
+            .or_default() += 1;
     }
     title_counts
         .into_iter()
@@ -296,34 +1810,204 @@ fn get_duplicate_titles(documents: &[Paper]) -> Vec<String> {
         .collect()
 }
 
+/// Word-set Jaccard similarity above which two highlights are flagged as possible duplicates.
+const NEAR_DUPLICATE_THRESHOLD: f64 = 0.9;
+
+fn word_set(text: &str) -> HashSet<String> {
+    text.split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn jaccard_similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Removes highlights whose trimmed content exactly repeats an earlier one,
+/// keeping the first occurrence. Near-duplicates are kept but flagged.
+/// Standalone notes have no content and are always kept.
+fn deduplicate_highlights(highlights: &[HighlightJson]) -> Vec<HighlightJson> {
+    let mut seen_contents: HashSet<&str> = HashSet::new();
+    let mut kept: Vec<HighlightJson> = Vec::new();
+    let mut kept_words: Vec<HashSet<String>> = Vec::new();
+
+    for highlight in highlights {
+        if highlight.annotation_type == AnnotationType::StandaloneNote {
+            kept.push(highlight.clone());
+            kept_words.push(HashSet::new());
+            continue;
+        }
+        if !seen_contents.insert(highlight.content.trim()) {
+            continue;
+        }
+        let mut highlight = highlight.clone();
+        let words = word_set(&highlight.content);
+        for (index, other_words) in kept_words.iter().enumerate() {
+            if jaccard_similarity(&words, other_words) > NEAR_DUPLICATE_THRESHOLD {
+                tracing::warn!(
+                    "Highlights {} and {} look like duplicates, keeping both",
+                    kept[index].id,
+                    highlight.id
+                );
+                kept[index].possible_duplicate = true;
+                highlight.possible_duplicate = true;
+            }
+        }
+        kept.push(highlight);
+        kept_words.push(words);
+    }
+    kept
+}
+
+fn highlights_context(
+    highlights_with_notes: &[HighlightJson],
+    omitted_notice: Option<&str>,
+) -> Context {
+    let mut highlight_context = Context::new();
+    highlight_context.insert("highlights", highlights_with_notes);
+    if let Some(omitted_notice) = omitted_notice {
+        highlight_context.insert("omitted_notice", omitted_notice);
+    }
+    highlight_context.insert("highlight_format", SETTINGS.highlight_format.name());
+    highlight_context.insert("note_format", &SETTINGS.note_format);
+    if let Some(prefix) = &SETTINGS.highlight_prefix {
+        highlight_context.insert("highlight_prefix", prefix);
+    }
+    if let Some(suffix) = &SETTINGS.highlight_suffix {
+        highlight_context.insert("highlight_suffix", suffix);
+    }
+    if let HighlightFormat::Drawer(drawer) = &SETTINGS.highlight_format {
+        highlight_context.insert("highlight_drawer", drawer);
+    }
+    if let Some(end_marker) = &SETTINGS.highlights_end_marker {
+        highlight_context.insert("highlights_end_marker", end_marker);
+    }
+    if let Some(drawer_name) = &SETTINGS.org_drawer_name {
+        highlight_context.insert("org_drawer_name", drawer_name);
+    }
+    highlight_context.insert(
+        "highlights_heading_level",
+        &SETTINGS.highlights_heading_level,
+    );
+    highlight_context.insert(
+        "highlights_heading_stars",
+        &SETTINGS.highlights_heading_stars(),
+    );
+    highlight_context
+}
+
 fn generate_highlight_content(
     highlights_with_notes: &[HighlightJson],
+    omitted_notice: Option<&str>,
     tera: &Tera,
+    template_name: &str,
 ) -> Result<String, tera::Error> {
     if highlights_with_notes.is_empty() {
         return Ok(String::new());
     }
-    let mut highlight_context = Context::new();
-    highlight_context.insert("highlights", highlights_with_notes);
-    tera.render("highlights.tera", &highlight_context)
+    tera.render(
+        template_name,
+        &highlights_context(highlights_with_notes, omitted_notice),
+    )
 }
 
-fn generate_file_content(
-    document: &Paper,
-    highlight_content: &str,
-    tera: &Tera,
-) -> Result<String, tera::Error> {
-    let uuid = Uuid::new_v4().to_string();
+/// The line telling that some highlights were left out of a file, if any were.
+fn omitted_notice(omitted: usize, reason: &str) -> Option<String> {
+    (omitted > 0).then(|| format!("[{} highlights omitted due to {}]", omitted, reason))
+}
+
+/// Returns how many of the first `count` highlights fit in a file of at most
+/// `max_bytes`, given the size of the file rendered with the first N highlights.
+/// The size grows with each highlight, so this bisects instead of trying every N.
+fn highlights_fitting_size(
+    count: usize,
+    max_bytes: u64,
+    file_size: impl Fn(usize) -> Result<u64, tera::Error>,
+) -> Result<usize, tera::Error> {
+    if file_size(count)? <= max_bytes {
+        return Ok(count);
+    }
+    let (mut low, mut high) = (0, count - 1);
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        if file_size(middle)? <= max_bytes {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(low)
+}
+
+fn document_context(document: &Paper, highlight_content: &str) -> Context {
+    // Derived from the ref, so that the same paper always gets the same ID
+    let uuid = Uuid::new_v5(&UUID_NAMESPACE, document.roam_ref.as_bytes()).to_string();
 
     let mut context = Context::new();
     context.insert("uuid", &uuid);
+    context.insert("id_property", &SETTINGS.org_id_property);
+    context.insert("roam_refs_property", &SETTINGS.org_roam_ref_property);
     context.insert("roam_ref", &document.roam_ref);
+    context.insert("custom_properties", &SETTINGS.custom_properties);
+    context.insert("static_tags", &SETTINGS.roam_tags);
+    let related_refs: Vec<&str> = document
+        .related
+        .iter()
+        .map(|related| related.roam_ref.as_str())
+        .collect();
+    context.insert("related_refs", &related_refs);
+    context.insert("related_papers", &document.related);
     if document.has_url {
         context.insert("full_url", &document.source_url);
     }
     context.insert("zotero_url", &document.zotero_url);
+    context.insert("link_format", &SETTINGS.link_format);
+    context.insert("org_roam_version", &SETTINGS.org_roam_version);
+    context.insert("item_key", &document.item_key);
     context.insert("title", &document.title);
+    context.insert("title_original", &document.title_original);
+    if let Some(short_title) = &document.short_title {
+        context.insert("short_title", short_title);
+    }
+    if let Some(series) = &document.series {
+        context.insert("series", series);
+    }
+    if let Some(collection_path) = &document.collection_path {
+        context.insert("collection_path", collection_path);
+    }
+    if let Some(series_number) = &document.series_number {
+        context.insert("series_number", series_number);
+    }
+    if let Some(volume_number) = &document.volume_number {
+        context.insert("volume_number", volume_number);
+    }
+    if let Some(number_of_volumes) = &document.number_of_volumes {
+        context.insert("number_of_volumes", number_of_volumes);
+    }
+    if let Some(pages) = &document.pages {
+        context.insert("pages", pages);
+    }
+    if let Some(page_count) = document.page_count {
+        context.insert("page_count", &page_count);
+    }
+    if let Some(length_display) = document.length_display() {
+        context.insert("length_display", &length_display);
+    }
     context.insert("authors", &document.author);
+    context.insert("author_list", &document.authors);
+    context.insert("editors", &format_author_list(&document.editors, &SETTINGS));
+    context.insert("editor_list", &document.editors);
+    context.insert(
+        "translators",
+        &format_author_list(&document.translators, &SETTINGS),
+    );
+    context.insert("translator_list", &document.translators);
+    context.insert("tags", &document.tags);
+    context.insert("tags_filtered", &document.tags_filtered);
     context.insert(
         "saved_at",
         &document.saved_at.format("%Y-%m-%d").to_string(),
@@ -334,29 +2018,294 @@ fn generate_file_content(
             &published_date.format("%Y-%m-%d").to_string(),
         );
     }
+    if let Some(publication_year) = document.publication_year {
+        context.insert("publication_year", &publication_year);
+        context.insert("year", &publication_year);
+    }
+    if let Some(date_accessed) = document.date_accessed {
+        context.insert("date_accessed", &date_accessed);
+        context.insert(
+            "date_accessed_str",
+            &date_accessed.format("%Y-%m-%d").to_string(),
+        );
+    }
+    if let Some(attachment_path) = &document.attachment_path {
+        let label = match (
+            &SETTINGS.attachment_link_text,
+            document.attachment_content_type.as_deref(),
+        ) {
+            (Some(text), _) => text.as_str(),
+            (None, Some(PDF_CONTENT_TYPE)) => "Open PDF",
+            (None, Some("application/epub+zip")) => "Open EPUB",
+            (None, Some("text/html")) => "Open snapshot",
+            (None, _) => "Open attachment",
+        };
+        let link = if label.is_empty() {
+            format!("[[file:{}]]", attachment_path.display())
+        } else {
+            format!("[[file:{}][{}]]", attachment_path.display(), label)
+        };
+        context.insert("attachment_link_text", label);
+        if document.attachment_is_pdf {
+            context.insert("pdf_link", &link);
+        }
+        context.insert("attachment_link", &link);
+        context.insert("attachment_filename", &document.attachment_filename);
+        context.insert("attachment_content_type", &document.attachment_content_type);
+    }
+    context.insert("attachment_is_pdf", &document.attachment_is_pdf);
+    context.insert("has_pdf", &document.has_pdf);
+    context.insert("note_count", &document.note_count);
+    context.insert("has_highlights", &document.has_highlights);
+    context.insert("highlight_count", &document.highlight_count);
     context.insert("highlight_content", highlight_content);
-    tera.render("document.org.tera", &context)
+    context
 }
 
-fn edit_file(
-    filename: &str,
-    _parent: &Paper,
+fn generate_file_content(
+    document: &Paper,
     highlight_content: &str,
-) -> Result<bool, std::io::Error> {
-    let content = fs::read_to_string(filename)?;
-    let lines: Vec<&str> = content.lines().collect();
-
-    let highlight_marker = "* zotero:highlights";
+    child_notes: &[String],
+    tera: &Tera,
+) -> Result<String, tera::Error> {
+    let mut context = document_context(document, highlight_content);
+    context.insert("child_notes", child_notes);
+    if let Some(paper_heading) = paper_heading(&context)? {
+        context.insert("paper_heading", &paper_heading);
+    }
+    let content = tera.render(SETTINGS.new_file_template(), &context)?;
+    Ok(match &SETTINGS.file_header {
+        Some(file_header) => format!("{}{}", file_header_comment(file_header), content),
+        None => content,
+    })
+}
 
-    let highlight_start_index = lines
+/// Turns the `file_header` setting into org comment lines, adding `# ` to the
+/// lines that are not comments yet. Org allows comments before the file-level
+/// property drawer, so org-roam still sees the node.
+fn file_header_comment(file_header: &str) -> String {
+    file_header
+        .lines()
+        .map(|line| {
+            if line.starts_with('#') {
+                format!("{}\n", line)
+            } else {
+                format!("# {}\n", line)
+            }
+        })
+        .collect()
+}
+
+/// Renders the `paper_heading_format` setting with the variables of the document.
+fn paper_heading(context: &Context) -> Result<Option<String>, tera::Error> {
+    SETTINGS
+        .paper_heading_format
+        .as_ref()
+        .map(|format| {
+            Tera::one_off(format, context, false).map(|heading| heading.trim().to_string())
+        })
+        .transpose()
+}
+
+/// A context with every variable set, used to render templates in `check-templates`.
+fn sample_template_context() -> Context {
+    let paper = PaperBuilder::new()
+        .title("A Sample Paper")
+        .short_title("Sample")
+        .author("Ada", "Lovelace", Some("0000-0000-0000-0000"))
+        .editor("Charles", "Babbage")
+        .translator("Luigi", "Menabrea")
+        .tag("machine learning")
+        .tag("_tablet")
+        .url("https://example.com/paper")
+        .published_date(Utc::now())
+        .attachment("/tmp/sample-paper.pdf", PDF_CONTENT_TYPE)
+        .note_count(1)
+        .highlight_count(1)
+        .build(&SETTINGS);
+    let highlights = vec![HighlightJson {
+        id: "2".to_string(),
+        annotation_key: "EFGH5678".to_string(),
+        color: Some("#ffd400".to_string()),
+        sort_index: "00001|000100|00050".to_string(),
+        date_added: "2024-01-01".to_string(),
+        content: "A sample highlight.".to_string(),
+        highlight_original_length: 19,
+        note: "A sample note.".to_string(),
+        note_saved_at: "2024-01-01".to_string(),
+        possible_duplicate: false,
+        annotation_type: AnnotationType::Highlight,
+    }];
+    let highlight_content = format!(
+        "{}\n{}* zotero:2\nA sample highlight.",
+        SETTINGS.highlights_heading(),
+        SETTINGS.highlights_heading_stars()
+    );
+
+    let mut context = document_context(&paper, &highlight_content);
+    context.insert(
+        "child_notes",
+        &child_notes_org(&[NoteContent {
+            key: "IJKL9012".to_string(),
+            html: "<p>A sample <strong>child note</strong>.</p>".to_string(),
+        }]),
+    );
+    if let Ok(Some(paper_heading)) = paper_heading(&context) {
+        context.insert("paper_heading", &paper_heading);
+    }
+    context.extend(highlights_context(
+        &highlights,
+        omitted_notice(3, "file size limit").as_deref(),
+    ));
+    context
+}
+
+const UTF8_BOM: &[u8] = "\u{FEFF}".as_bytes();
+
+/// Reads an org file written in the configured `file_encoding`.
+fn read_org_file(path: impl AsRef<Path>) -> io::Result<String> {
+    match SETTINGS.file_encoding {
+        FileEncoding::Utf8 => fs::read_to_string(path),
+        FileEncoding::Utf8Bom => {
+            let bytes = fs::read(path)?;
+            let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(&bytes);
+            String::from_utf8(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        FileEncoding::Latin1 => {
+            let bytes = fs::read(path)?;
+            let (content, _) = encoding_rs::WINDOWS_1252.decode_without_bom_handling(&bytes);
+            Ok(content.into_owned())
+        }
+    }
+}
+
+/// Writes an org file in the configured `file_encoding`, with the configured
+/// `file_permissions`. In Latin-1, characters that can't be encoded are written
+/// as HTML numeric character references.
+fn write_org_file(path: impl AsRef<Path>, content: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    match SETTINGS.file_encoding {
+        FileEncoding::Utf8 => fs::write(path, content)?,
+        FileEncoding::Utf8Bom => fs::write(path, [UTF8_BOM, content.as_bytes()].concat())?,
+        FileEncoding::Latin1 => {
+            let (bytes, _, _) = encoding_rs::WINDOWS_1252.encode(content);
+            fs::write(path, bytes)?
+        }
+    }
+    if let Some(mode) = SETTINGS.file_permissions {
+        set_file_permissions(path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_file_permissions(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Only Unix has permission modes
+#[cfg(not(unix))]
+fn set_file_permissions(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns the index of the line after the `:END:` closing the drawer opened on
+/// line `start`, skipping drawers nested in it, e.g. `:PROPERTIES:` drawers.
+fn drawer_end_index(lines: &[&str], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        let line = line.trim();
+        if line.eq_ignore_ascii_case(":END:") {
+            depth -= 1;
+            if depth == 0 {
+                return Some(index + 1);
+            }
+        } else if line.len() > 2
+            && line.starts_with(':')
+            && line.ends_with(':')
+            && !line[1..line.len() - 1].contains([':', ' '])
+        {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// Returns the range of lines of the highlights section of `filename`, empty
+/// at the end of the file when it has none.
+fn highlights_section_bounds(lines: &[&str], filename: &str) -> (usize, usize) {
+    // The highlights are either under a heading or in a drawer
+    let drawer_marker = SETTINGS
+        .org_drawer_name
+        .as_ref()
+        .map(|drawer_name| format!(":{}:", drawer_name));
+    let in_drawer = drawer_marker.is_some();
+    let highlight_marker = drawer_marker.unwrap_or_else(|| SETTINGS.highlights_heading());
+
+    let highlight_start_index = lines
         .iter()
         .position(|line| line.trim() == highlight_marker)
         .unwrap_or(lines.len());
 
-    let existing_highlight_section = lines[highlight_start_index..].join("\n");
+    // Without an end marker, the highlights section runs to the end of its drawer,
+    // or of the file
+    let highlight_end_index = match &SETTINGS.highlights_end_marker {
+        Some(end_marker) => match lines[highlight_start_index..]
+            .iter()
+            .position(|line| line.trim() == end_marker.trim())
+        {
+            Some(offset) => highlight_start_index + offset + 1,
+            None => {
+                if highlight_start_index < lines.len() {
+                    tracing::warn!(
+                        "End marker {:?} not found in {}, replacing everything after {:?}",
+                        end_marker,
+                        filename,
+                        highlight_marker
+                    );
+                }
+                lines.len()
+            }
+        },
+        None if in_drawer && highlight_start_index < lines.len() => {
+            drawer_end_index(lines, highlight_start_index).unwrap_or_else(|| {
+                tracing::warn!(
+                    "Drawer {:?} is not closed in {}, replacing everything after it",
+                    highlight_marker,
+                    filename
+                );
+                lines.len()
+            })
+        }
+        None => lines.len(),
+    };
+    (highlight_start_index, highlight_end_index)
+}
+
+/// Returns the highlights section of `filename`, empty when it has none.
+fn existing_highlights_section(filename: &str) -> io::Result<String> {
+    let content = read_org_file(filename)?;
+    let lines: Vec<&str> = content.lines().collect();
+    let (start, end) = highlights_section_bounds(&lines, filename);
+    Ok(lines[start..end].join("\n"))
+}
+
+/// Returns the new content of `filename` with its highlights section replaced by
+/// `highlight_content`, or `None` if the file is already up to date.
+fn get_edited_content(
+    filename: &str,
+    highlight_content: &str,
+) -> Result<Option<String>, std::io::Error> {
+    let content = read_org_file(filename)?;
+    let lines: Vec<&str> = content.lines().collect();
+    let (highlight_start_index, highlight_end_index) = highlights_section_bounds(&lines, filename);
+
+    let existing_highlight_section = lines[highlight_start_index..highlight_end_index].join("\n");
 
     if existing_highlight_section.trim() == highlight_content.trim() {
-        return Ok(false);
+        return Ok(None);
     }
 
     let new_content_lines = lines[..highlight_start_index].to_vec();
@@ -369,35 +2318,117 @@ fn edit_file(
 
     new_content.push_str(highlight_content);
 
-    fs::write(filename, new_content)?;
-    Ok(true)
+    let trailing_lines = &lines[highlight_end_index..];
+    if !trailing_lines.is_empty() {
+        if !new_content.is_empty() && !new_content.ends_with('\n') {
+            new_content.push('\n');
+        }
+        new_content.push_str(&trailing_lines.join("\n"));
+        new_content.push('\n');
+    }
+
+    Ok(Some(new_content))
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let start_time = std::time::Instant::now();
+fn print_stats(papers: &[Paper], highlight_counts: &HashMap<String, usize>) {
+    println!("--- Library statistics ---");
+    println!("Papers: {}", papers.len());
 
-    let tera = Tera::new(&SETTINGS.templates_dir.to_string_lossy())?;
+    let mut type_counts: HashMap<&str, usize> = HashMap::new();
+    for paper in papers {
+        *type_counts.entry(paper.item_type.as_str()).or_default() += 1;
+    }
+    let mut type_counts: Vec<(&str, usize)> = type_counts.into_iter().collect();
+    type_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("\nPapers by item type:");
+    for (item_type, count) in &type_counts {
+        println!("  {}: {}", item_type, count);
+    }
 
-    let org_roam_dir = Path::new(&SETTINGS.org_roam_dir);
-    if !org_roam_dir.is_dir() {
-        eprintln!("Org roam directory not found: {}", org_roam_dir.display());
-        return Err(format!("Org roam directory not found: {}", org_roam_dir.display()).into());
+    let mut counts: Vec<(&Paper, usize)> = papers
+        .iter()
+        .map(|paper| (paper, highlight_counts.get(&paper.id).copied().unwrap_or(0)))
+        .collect();
+    let total_annotations: usize = counts.iter().map(|(_, count)| count).sum();
+    println!("\nTotal annotations: {}", total_annotations);
+
+    if counts.is_empty() {
+        return;
+    }
+    let mean = total_annotations as f64 / counts.len() as f64;
+    let mut sorted_counts: Vec<usize> = counts.iter().map(|(_, count)| *count).collect();
+    sorted_counts.sort_unstable();
+    let mid = sorted_counts.len() / 2;
+    let median = if sorted_counts.len().is_multiple_of(2) {
+        (sorted_counts[mid - 1] + sorted_counts[mid]) as f64 / 2.0
+    } else {
+        sorted_counts[mid] as f64
+    };
+    println!(
+        "Highlights per paper: mean {:.1}, median {:.1}",
+        mean, median
+    );
+
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    println!("\nPapers with the most highlights:");
+    for (paper, count) in counts.iter().take(5).filter(|(_, count)| *count > 0) {
+        println!("  {:>4}  {}", count, paper.title);
+    }
+}
+
+/// Emits an error event for `paper` and records it for the run summary.
+fn report_error(
+    reporter: &Reporter,
+    run_errors: &mut Vec<RunError>,
+    paper: &Paper,
+    message: String,
+    path: Option<&str>,
+) {
+    reporter.emit(&Event::Error {
+        message: message.clone(),
+        path,
+        paper_id: Some(&paper.id),
+    });
+    run_errors.push(RunError {
+        paper_id: paper.id.clone(),
+        title: paper.title.clone(),
+        error_message: message,
+    });
+}
+
+/// Last modification time of the Zotero database, to tell whether the library
+/// changed since a previous run. `None` when reading from the local API.
+fn zotero_db_mtime() -> Option<String> {
+    if SETTINGS.zotero_local_api_port.is_some() {
+        return None;
     }
+    let modified = fs::metadata(&SETTINGS.zotero_db_path)
+        .ok()?
+        .modified()
+        .ok()?;
+    Some(DateTime::<Local>::from(modified).to_rfc3339())
+}
 
+/// Copies the Zotero database to a temporary file, so that it can be read
+/// while Zotero is running.
+fn copy_zotero_db(reporter: &Reporter) -> Result<PathBuf, std::io::Error> {
     let original_db_path = Path::new(&SETTINGS.zotero_db_path);
     let temp_dir = env::temp_dir();
     let temp_filename = format!("zotero_db_copy_{}.sqlite", Uuid::new_v4());
     let temp_db_path = temp_dir.join(&temp_filename);
 
-    println!(
+    reporter.info(format!(
         "Copying Zotero database to temporary location: {}",
         temp_db_path.display()
-    );
+    ));
     match fs::copy(original_db_path, &temp_db_path) {
-        Ok(_) => println!(
-            "Database copied successfully to: {}",
-            temp_db_path.display()
-        ),
+        Ok(_) => {
+            reporter.info(format!(
+                "Database copied successfully to: {}",
+                temp_db_path.display()
+            ));
+            Ok(temp_db_path)
+        }
         Err(e) => {
             eprintln!(
                 "Failed to copy Zotero database from {} to {}: {}",
@@ -406,102 +2437,1528 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 e
             );
             let _ = fs::remove_file(&temp_db_path);
-            return Err(Box::new(e));
+            Err(e)
         }
     }
+}
 
-    let conn = match Connection::open_with_flags(
-        &temp_db_path,
-        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
-    ) {
-        Ok(c) => c,
+/// Runs `emacs_eval_after_sync` with `emacs_command`, e.g.
+/// `emacsclient --eval '(org-roam-db-sync)'`, so that org-roam sees the changes.
+fn run_emacs_eval(emacs_command: &str, reporter: &Reporter) {
+    let expression = SETTINGS
+        .emacs_eval_after_sync
+        .as_deref()
+        .unwrap_or(DEFAULT_EMACS_EVAL);
+    // The command may come with its own arguments, e.g. "emacsclient -s work"
+    let mut words = emacs_command.split_whitespace();
+    let Some(program) = words.next() else {
+        return;
+    };
+    reporter.info(format!("Running {} --eval '{}'", emacs_command, expression));
+    match Command::new(program)
+        .args(words)
+        .args(["--eval", expression])
+        .output()
+    {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => reporter.warn(format!(
+            "{} --eval failed ({}): {}",
+            emacs_command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => reporter.warn(format!("Could not run {}: {}", emacs_command, e)),
+    }
+}
+
+/// Evaluated after a sync when `emacs_eval_after_sync` is not set.
+const DEFAULT_EMACS_EVAL: &str = "(org-roam-db-sync)";
+
+/// The copy of the Zotero database made by `copy_zotero_db`, removed when
+/// dropped so that runs ending early or with an error don't leave it behind.
+struct TempDb<'a> {
+    path: PathBuf,
+    reporter: &'a Reporter,
+}
+
+impl Drop for TempDb<'_> {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(_) => self.reporter.info(format!(
+                "Cleaned up temporary database: {}",
+                self.path.display()
+            )),
+            Err(e) => eprintln!(
+                "Warning: Failed to clean up temporary database {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+fn run_migrations(
+    org_roam_dir: &Path,
+    from: Version,
+    to: Version,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chain = migrations::migrations_between(from, to)?;
+    let preview_dir = if dry_run { dry_run_preview_dir() } else { None };
+    if chain.is_empty() {
+        println!("No migrations needed from {} to {}.", from, to);
+        return Ok(());
+    }
+
+    let mut filenames: Vec<String> = get_existing_refs(org_roam_dir, |refs_found| {
+        println!("{}", scan_progress_message(refs_found))
+    })?
+    .into_values()
+    .collect::<HashSet<_>>()
+    .into_iter()
+    .collect();
+    filenames.sort();
+
+    let mut files_migrated = 0;
+    for filename in &filenames {
+        let content = read_org_file(filename)?;
+        let migrated = migrations::apply_migrations(&content, &chain);
+        if migrated == content {
+            continue;
+        }
+        if let Some(preview_dir) = &preview_dir {
+            let preview_path = write_preview(preview_dir, org_roam_dir, filename, &migrated)?;
+            println!(
+                "Would migrate file: {} (preview: {})",
+                filename,
+                preview_path.display()
+            );
+        } else if dry_run {
+            println!("Would migrate file: {}", filename);
+        } else {
+            write_org_file(filename, &migrated)?;
+            println!("Migrated file: {}", filename);
+        }
+        files_migrated += 1;
+    }
+    println!(
+        "{} of {} files {} from {} to {}.",
+        files_migrated,
+        filenames.len(),
+        if dry_run {
+            "would be migrated"
+        } else {
+            "migrated"
+        },
+        from,
+        to
+    );
+    if let (Some(preview_dir), true) = (&preview_dir, files_migrated > 0) {
+        println!("{}", preview_diff_hint(preview_dir, org_roam_dir));
+    }
+    Ok(())
+}
+
+/// A new subdirectory of `dry_run_output_dir` for the files of a dry run, named
+/// after the current time so that earlier previews are never overwritten.
+fn dry_run_preview_dir() -> Option<PathBuf> {
+    SETTINGS
+        .dry_run_output_dir
+        .as_ref()
+        .map(|dir| dir.join(Local::now().format("%Y-%m-%dT%H-%M-%S").to_string()))
+}
+
+/// Writes what a dry run would write to `filename` under `preview_dir`, at the
+/// same path relative to `org_roam_dir`, and returns the path written.
+fn write_preview(
+    preview_dir: &Path,
+    org_roam_dir: &Path,
+    filename: &str,
+    content: &str,
+) -> io::Result<PathBuf> {
+    let path = Path::new(filename);
+    let relative_path = match path.strip_prefix(org_roam_dir) {
+        Ok(relative_path) => relative_path,
+        // Joining an absolute path would write outside of the preview directory
+        Err(_) => Path::new(path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a file: {}", filename),
+            )
+        })?),
+    };
+    let preview_path = preview_dir.join(relative_path);
+    if let Some(parent) = preview_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_org_file(&preview_path, content)?;
+    Ok(preview_path)
+}
+
+fn preview_diff_hint(preview_dir: &Path, org_roam_dir: &Path) -> String {
+    format!(
+        "Files written to {}, compare them with: diff -r {} {}",
+        preview_dir.display(),
+        preview_dir.display(),
+        org_roam_dir.display()
+    )
+}
+
+/// Reports a file that `--dry-run` would create or edit, and writes its content
+/// to `preview_dir` when `dry_run_output_dir` is set.
+fn report_dry_run_file(
+    reporter: &Reporter,
+    preview_dir: Option<&Path>,
+    org_roam_dir: &Path,
+    action: FileAction,
+    filename: &str,
+    content: &str,
+) -> io::Result<()> {
+    match preview_dir {
+        Some(preview_dir) => {
+            let preview_path = write_preview(preview_dir, org_roam_dir, filename, content)?;
+            reporter.info(format!(
+                "Would {} file: {} (preview: {})",
+                action,
+                filename,
+                preview_path.display()
+            ));
+        }
+        None => reporter.info(format!("Would {} file: {}", action, filename)),
+    }
+    Ok(())
+}
+
+/// The `.org` files of `dir` and its subdirectories, skipping hidden ones like
+/// `.git`, in a stable order.
+fn org_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "org") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Rescans `org_roam_dir` file by file for refs, reporting refs found in
+/// several files. With `org_roam_db_path`, also checks the refs org-roam's
+/// database has cached, which `get_existing_refs` relies on: entries whose file
+/// is gone or doesn't hold the ref anymore are stale, and `emacs_command`, when
+/// set, rebuilds the database. Returns whether no stale entry is left.
+fn run_reindex(
+    org_roam_dir: &Path,
+    reporter: &Reporter,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let property = format!(":{}:", SETTINGS.org_roam_ref_property);
+    let files = org_files(org_roam_dir)?;
+    let mut scanned_refs: HashMap<String, String> = HashMap::new();
+    for file in &files {
+        let filename = file.to_string_lossy().into_owned();
+        let content = match read_org_file(file) {
+            Ok(content) => content,
+            Err(e) => {
+                reporter.warn(format!("Could not read {}: {}", filename, e));
+                continue;
+            }
+        };
+        for roam_ref in content
+            .lines()
+            .filter_map(|line| line_roam_ref(line, &property))
+        {
+            if let Some(other) = scanned_refs.insert(roam_ref.to_string(), filename.clone()) {
+                if other != filename {
+                    reporter.warn(format!(
+                        "{} is the ref of both {} and {}",
+                        roam_ref, other, filename
+                    ));
+                }
+            }
+        }
+    }
+
+    let Some(org_roam_db_path) = &SETTINGS.org_roam_db_path else {
+        // Refs are searched with ripgrep on every run, there is nothing cached to fix
+        println!(
+            "{} files scanned, {} refs found, 0 stale entries removed",
+            files.len(),
+            scanned_refs.len()
+        );
+        return Ok(true);
+    };
+
+    let mut stale: Vec<(String, String)> = query_org_roam_refs(org_roam_db_path)?
+        .into_iter()
+        .filter(|(roam_ref, file)| {
+            !Path::new(file).exists() || scanned_refs.get(roam_ref) != Some(file)
+        })
+        .collect();
+    stale.sort();
+    for (roam_ref, file) in &stale {
+        reporter.warn(format!(
+            "Stale entry in org-roam's database: {} in {}",
+            roam_ref, file
+        ));
+    }
+    let removed = match (&SETTINGS.emacs_command, stale.is_empty()) {
+        (Some(emacs_command), false) => {
+            run_emacs_eval(emacs_command, reporter);
+            let synced_refs = query_org_roam_refs(org_roam_db_path)?;
+            stale
+                .iter()
+                .filter(|(roam_ref, file)| synced_refs.get(roam_ref) != Some(file))
+                .count()
+        }
+        _ => 0,
+    };
+    println!(
+        "{} files scanned, {} refs found, {} stale entries removed",
+        files.len(),
+        scanned_refs.len(),
+        removed
+    );
+    if removed < stale.len() {
+        reporter.warn(format!(
+            "{} stale entries are left in {}, run org-roam-db-sync in Emacs to remove them",
+            stale.len() - removed,
+            org_roam_db_path.display()
+        ));
+    }
+    Ok(removed == stale.len())
+}
+
+/// Reports the `[[file:...]]` links of the org files with a `:ROAM_REFS:` whose
+/// target doesn't exist, e.g. attachments moved by Zotero. Returns whether all
+/// links are valid.
+fn run_check_links(org_roam_dir: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let home_dir = PathBuf::from(env::var("HOME").unwrap_or_default());
+    let mut filenames: Vec<String> = get_existing_refs(org_roam_dir, |refs_found| {
+        println!("{}", scan_progress_message(refs_found))
+    })?
+    .into_values()
+    .collect::<HashSet<_>>()
+    .into_iter()
+    .collect();
+    filenames.sort();
+
+    let mut links_checked = 0;
+    let mut broken_links = 0;
+    for filename in &filenames {
+        let content = read_org_file(filename)?;
+        for link in links::file_links(&content) {
+            links_checked += 1;
+            let target = links::resolve_link_target(&link.target, Path::new(filename), &home_dir);
+            if !target.exists() {
+                tracing::warn!(
+                    "{}:{}: broken link to {}",
+                    filename,
+                    link.line,
+                    target.display()
+                );
+                broken_links += 1;
+            }
+        }
+    }
+    println!(
+        "{} of {} file links in {} files are broken.",
+        broken_links,
+        links_checked,
+        filenames.len()
+    );
+    Ok(broken_links == 0)
+}
+
+/// Why a paper looks like junk imported by a browser connector, e.g. a login
+/// page saved as "Sign In", or `None` for regular papers.
+fn junk_title_reason(title: &str) -> Option<String> {
+    let title = title.trim();
+    let length = title.chars().count();
+    if length < SETTINGS.min_title_length {
+        return Some(format!(
+            "title shorter than min_title_length ({} < {})",
+            length, SETTINGS.min_title_length
+        ));
+    }
+    SETTINGS
+        .junk_title_regexes
+        .iter()
+        .find(|pattern| pattern.is_match(title))
+        .map(|pattern| {
+            format!(
+                "title matches junk_title_patterns entry {:?}",
+                pattern.as_str()
+            )
+        })
+}
+
+/// Removes the papers with a junk title, with a warning giving the reason.
+fn skip_junk_papers(papers: &mut Vec<Paper>) {
+    papers.retain(|paper| match junk_title_reason(&paper.title_original) {
+        Some(reason) => {
+            tracing::warn!(
+                "Skipping paper {} \"{}\": {}",
+                paper.id,
+                paper.title_original,
+                reason
+            );
+            false
+        }
+        None => true,
+    });
+}
+
+/// Subdirectory of new files of papers in no collection, with `organize_by_collection`.
+const UNCATEGORIZED_DIR: &str = "uncategorized";
+
+/// Maximum number of connections opened on the copy of the Zotero database.
+const DB_POOL_SIZE: usize = 4;
+
+/// Pragmas accepted in the `sqlite_pragma` setting: those tuning how SQLite
+/// reads, as the database is opened read-only and with `query_only`.
+const KNOWN_SQLITE_PRAGMAS: &[&str] = &[
+    "automatic_index",
+    "busy_timeout",
+    "cache_size",
+    "cache_spill",
+    "case_sensitive_like",
+    "hard_heap_limit",
+    "mmap_size",
+    "soft_heap_limit",
+    "temp_store",
+    "threads",
+];
+
+/// The `sqlite_pragma` setting, sorted by name, without the pragmas that are
+/// unknown or have values that aren't a plain number or keyword, which are
+/// reported as warnings.
+fn sqlite_pragmas(reporter: &Reporter) -> Vec<(String, String)> {
+    let mut pragmas: Vec<(String, String)> = SETTINGS
+        .sqlite_pragma
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+        .collect();
+    pragmas.sort();
+    pragmas.retain(|(name, value)| {
+        if name == "query_only" {
+            reporter.warn("Ignoring sqlite_pragma query_only: the Zotero database is always read with query_only = ON");
+            return false;
+        }
+        if !KNOWN_SQLITE_PRAGMAS.contains(&name.as_str()) {
+            reporter.warn(format!(
+                "Ignoring unknown sqlite_pragma {}, expected one of: {}",
+                name,
+                KNOWN_SQLITE_PRAGMAS.join(", ")
+            ));
+            return false;
+        }
+        let is_plain_value = !value.is_empty()
+            && value
+                .chars()
+                .enumerate()
+                .all(|(i, c)| c.is_ascii_alphanumeric() || c == '_' || (i == 0 && c == '-'));
+        if !is_plain_value {
+            reporter.warn(format!(
+                "Ignoring sqlite_pragma {} = {:?}: values must be a number or a keyword",
+                name, value
+            ));
+        }
+        is_plain_value
+    });
+    pragmas
+}
+
+/// The configured templates, with what they are used for.
+fn templates_in_use() -> [(&'static str, &'static str); 3] {
+    [
+        ("new files", SETTINGS.new_file_template()),
+        ("highlights", SETTINGS.highlights_template()),
+        ("updates", SETTINGS.update_file_template()),
+    ]
+}
+
+/// The `--version` line, with what is useful in bug reports, e.g.
+/// `org-zotero-rust 0.1.0 (Zotero schema v86, templates: /path/to/templates)`.
+fn version_info() -> String {
+    let version = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    // Without a usable config.toml, only the version is known
+    let settings = match settings::load_settings() {
+        Ok(settings) => settings,
         Err(e) => {
-            let _ = fs::remove_file(&temp_db_path);
-            return Err(Box::new(e));
+            tracing::debug!("Could not load the settings: {}", e);
+            return format!("{} (Zotero schema unknown, templates: unknown)", version);
         }
     };
+    let source = match settings.zotero_local_api_port {
+        Some(port) => format!("Zotero local API on port {}", port),
+        // Read in place: the version is a single query, not worth copying the database for
+        None => match Connection::open_with_flags(
+            &settings.zotero_db_path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )
+        .and_then(|conn| detect_zotero_schema_version(&conn))
+        {
+            Ok(schema_version) => format!("Zotero schema v{}", schema_version),
+            Err(e) => {
+                tracing::debug!("Could not detect the Zotero schema version: {}", e);
+                "Zotero schema unknown".to_string()
+            }
+        },
+    };
+    format!(
+        "{} ({}, templates: {})",
+        version,
+        source,
+        settings.templates_dir.display()
+    )
+}
 
-    println!("Scanning {:?} for existing refs...", org_roam_dir);
-    let existing_refs = get_existing_refs(org_roam_dir)?;
-    println!("Found {} existing org-roam refs.", existing_refs.len());
+/// Prints the JSON Schema of the settings, for editors to validate and complete
+/// config.toml.
+#[cfg(feature = "schema")]
+fn print_config_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let schema = schemars::schema_for!(settings::Settings);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "schema"))]
+fn print_config_schema() -> Result<(), Box<dyn std::error::Error>> {
+    eprintln!("This build has no config schema support, rebuild it with `--features schema`");
+    std::process::exit(1);
+}
 
-    println!("Querying papers from Zotero DB...");
-    let papers = query_papers(&conn)?;
-    println!("Found {} papers with potential attachments.", papers.len());
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let color = if cli.no_color {
+        ColorChoice::Never
+    } else {
+        cli.color
+    };
+    color.apply();
+    let mut log_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    if cli.verbose_sql {
+        VERBOSE_SQL.store(true, Ordering::Relaxed);
+        log_filter = log_filter.add_directive("sql=debug".parse()?);
+    }
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(log_filter)
+        .with_ansi(color.enabled_for(&console::Term::stderr()))
+        .init();
+    if cli.version {
+        println!("{}", version_info());
+        return Ok(());
+    }
+    if let Some(shell) = cli.generate_completions {
+        let mut command = Cli::command();
+        let bin_name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, bin_name, &mut io::stdout());
+        return Ok(());
+    }
+
+    if cli.generate_config_schema {
+        print_config_schema()?;
+        return Ok(());
+    }
+
+    if let Some(Commands::ListTemplates) = cli.command {
+        templates::list_templates(&SETTINGS.templates_dir, &templates_in_use());
+        return Ok(());
+    }
+
+    if let Some(Commands::CheckTemplates) = cli.command {
+        let all_ok =
+            templates::check_templates(&SETTINGS.templates_dir, &sample_template_context())?;
+        if !all_ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if cli.print_sql {
+        print_sql();
+        return Ok(());
+    }
+
+    if let Some(Commands::Reindex) = cli.command {
+        let reporter = Reporter::new(cli.output_format);
+        if !run_reindex(&SETTINGS.org_roam_dir, &reporter)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Commands::CheckLinks) = cli.command {
+        if !run_check_links(&SETTINGS.org_roam_dir)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(versions) = &cli.migrate {
+        return run_migrations(
+            &SETTINGS.org_roam_dir,
+            versions[0],
+            versions[1],
+            cli.dry_run,
+        );
+    }
+
+    let filter_since = match &cli.filter_since {
+        Some(since) => match parse_date(since) {
+            Some(since) => Some(since),
+            None => {
+                eprintln!(
+                    "Invalid --filter-since date {:?}, expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM:SS\"",
+                    since
+                );
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let start_time = Instant::now();
+    let run_at = Local::now();
+    let reporter = Reporter::new(cli.output_format).quiet(cli.export_json);
+
+    let mut tera = Tera::new(&SETTINGS.templates_dir.to_string_lossy())?;
+    templates::register_filters(&mut tera);
+    // Checked here rather than failing on the first paper with a less helpful error
+    if !templates::report_missing_templates(&tera, &SETTINGS.templates_dir, &templates_in_use()) {
+        eprintln!(
+            "Add the missing templates, or change templates_dir or the *_template settings \
+             in ~/.config/org-zotero-rust/config.toml"
+        );
+        std::process::exit(1);
+    }
+    if SETTINGS.file_encoding == FileEncoding::Latin1 {
+        tracing::warn!(
+            "file_encoding is latin1, but org-roam only reads UTF-8 files: \
+             non-ASCII characters will be garbled in org-roam"
+        );
+    }
+
+    let org_roam_dir = Path::new(&SETTINGS.org_roam_dir);
+    if !org_roam_dir.is_dir() {
+        eprintln!("Org roam directory not found: {}", org_roam_dir.display());
+        return Err(format!("Org roam directory not found: {}", org_roam_dir.display()).into());
+    }
+    let lock = match LockFile::acquire(org_roam_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Could not acquire lock, is another instance running? {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut temp_db = None;
+    let backend: Box<dyn ZoteroBackend> = match SETTINGS.zotero_local_api_port {
+        Some(port) => {
+            if cli.validate_db {
+                reporter
+                    .warn("--validate-db only applies to the Zotero database, not the local API");
+            }
+            reporter.info(format!(
+                "Reading the Zotero library from the local API on port {}",
+                port
+            ));
+            if !SETTINGS.skip_item_ids.is_empty() {
+                reporter.warn(
+                    "skip_item_ids is ignored with the local API, which has no item IDs: \
+                     use skip_zotero_keys instead",
+                );
+            }
+            Box::new(LocalApiBackend::new(port))
+        }
+        None => {
+            let db_copy = TempDb {
+                path: copy_zotero_db(&reporter)?,
+                reporter: &reporter,
+            };
+            let pool = ConnectionPool::new(
+                &db_copy.path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+                DB_POOL_SIZE,
+            )
+            .with_pragmas(sqlite_pragmas(&reporter));
+            let backend = SqliteBackend::new(pool)?;
+            if cli.validate_db || !schema::is_validated(org_roam_dir) {
+                let missing = backend.missing_schema_items()?;
+                if !missing.is_empty() {
+                    eprintln!(
+                        "The Zotero database at {} doesn't have the expected schema:",
+                        SETTINGS.zotero_db_path.display()
+                    );
+                    for item in &missing {
+                        eprintln!("  missing {}", item);
+                    }
+                    // process::exit skips destructors
+                    drop(backend);
+                    drop(db_copy);
+                    drop(lock);
+                    std::process::exit(1);
+                }
+                reporter.info("Zotero database schema validated");
+                if let Err(e) = schema::mark_validated(org_roam_dir, backend.schema_version()) {
+                    reporter.warn(format!("Failed to write {}: {}", schema::STATE_FILENAME, e));
+                }
+            }
+            temp_db = Some(db_copy);
+            Box::new(backend)
+        }
+    };
+
+    if cli.stats_only {
+        // Counting is enough, without loading the highlights themselves
+        let mut papers = backend.papers()?;
+        skip_junk_papers(&mut papers);
+        print_stats(&papers, &backend.highlight_counts()?);
+        return Ok(());
+    }
+
+    if let Some(output) = &cli.export_org_table {
+        let mut papers = backend.papers()?;
+        skip_junk_papers(&mut papers);
+        set_highlight_counts(&mut papers, &backend.highlight_counts()?);
+        write_org_file(output, &export::org_table(&papers))?;
+        reporter.info(format!(
+            "Wrote a table of {} papers to {}",
+            papers.len(),
+            output.display()
+        ));
+        return Ok(());
+    }
+
+    if cli.export_json {
+        let mut papers = backend.papers()?;
+        skip_junk_papers(&mut papers);
+        set_highlight_counts(&mut papers, &backend.highlight_counts()?);
+        export::print_papers(&papers)?;
+        return Ok(());
+    }
+
+    reporter.info(format!("Scanning {:?} for existing refs...", org_roam_dir));
+    let existing_refs = get_existing_refs(org_roam_dir, |refs_found| {
+        reporter.info(scan_progress_message(refs_found))
+    })?;
+    reporter.info(format!(
+        "Found {} existing org-roam refs.",
+        existing_refs.len()
+    ));
+    if SETTINGS.zotero_local_api_port.is_some() {
+        let legacy_refs = existing_refs
+            .keys()
+            .filter(|roam_ref| {
+                roam_ref
+                    .strip_prefix("@zotero_")
+                    .is_some_and(|id| id.parse::<i64>().is_ok())
+            })
+            .count();
+        if legacy_refs > 0 {
+            reporter.warn(format!(
+                "{} files have @zotero_<itemID> refs from syncs with the database, which \
+                 the local API can't match: their papers get new files with @zotero_<key> refs",
+                legacy_refs
+            ));
+        }
+    }
+
+    reporter.info("Querying papers from Zotero DB...");
+    let mut papers = match filter_since {
+        Some(since) => {
+            reporter.info(format!("Only considering items modified since {}", since));
+            backend.papers_modified_since(since)?
+        }
+        None => backend.papers()?,
+    };
+    skip_junk_papers(&mut papers);
+    reporter.info(format!(
+        "Found {} papers with potential attachments.",
+        papers.len()
+    ));
     if papers.is_empty() {
-        println!("No papers found. Exiting.");
+        reporter.info("No papers found. Exiting.");
         return Ok(());
     }
 
-    println!("Querying highlights from Zotero DB...");
-    let highlights_map = query_highlights(&conn)?;
-    println!("Found highlights for {} papers.", highlights_map.len());
+    reporter.info("Querying highlights from Zotero DB...");
+    // When streaming, highlights are queried paper by paper in the loop below
+    // With --no-highlights, only the counts are needed, for --only-with-highlights
+    let load_highlights_upfront = !SETTINGS.stream_highlights && !cli.no_highlights;
+    let mut highlights_map = if load_highlights_upfront {
+        backend.highlights()?
+    } else {
+        HashMap::new()
+    };
+    let highlight_counts: HashMap<String, usize> = if load_highlights_upfront {
+        highlights_map
+            .iter()
+            .map(|(paper_id, highlights)| (paper_id.clone(), highlights.len()))
+            .collect()
+    } else if filter_since.is_some() {
+        // Counting the highlights of the few papers modified since then is cheaper
+        // than counting those of the whole library
+        let mut counts = HashMap::new();
+        for paper in &papers {
+            let count = backend.paper_highlight_count(&paper.id)?;
+            if count > 0 {
+                counts.insert(paper.id.clone(), count);
+            }
+        }
+        counts
+    } else {
+        backend.highlight_counts()?
+    };
+    reporter.info(format!(
+        "Found highlights for {} papers.",
+        highlight_counts.len()
+    ));
+    set_highlight_counts(&mut papers, &highlight_counts);
+    if cli.only_with_highlights {
+        papers.retain(|paper| paper.has_highlights);
+        reporter.info(format!("{} papers have highlights.", papers.len()));
+    }
+    let paper_ids: Vec<String> = papers.iter().map(|paper| paper.id.clone()).collect();
+    set_related_papers(&mut papers, &backend.related_items(&paper_ids)?);
 
     let duplicate_titles = get_duplicate_titles(&papers);
     if !duplicate_titles.is_empty() {
-        println!("Found duplicate titles: {:?}", duplicate_titles);
+        reporter.warn(format!("Found duplicate titles: {:?}", duplicate_titles));
     }
 
     let mut files_created = 0;
     let mut files_edited = 0;
+    let mut files_skipped = 0;
+    let mut run_errors: Vec<RunError> = Vec::new();
+    let mut files_out_of_date = 0;
+    // Files whose highlights section --compare-db-to-files compared
+    let mut files_compared = 0;
+    // Files --dry-run would create and edit
+    let mut files_would_create = 0;
+    let mut files_would_edit = 0;
+    let preview_dir = if cli.dry_run {
+        dry_run_preview_dir()
+    } else {
+        None
+    };
+    let mut prompter = Prompter::new(cli.interactive, cli.yes);
 
-    println!("Processing papers and generating/updating org files...");
+    // Node IDs of created files derive from the namespace, so it is saved before
+    // the first one. Runs that write nothing use a namespace for the run only.
+    if !(cli.dry_run || cli.assert_no_edits || cli.compare_db_to_files) {
+        match settings::save_uuid_namespace() {
+            Ok(Some(config_path)) => reporter.info(format!(
+                "Generated uuid_namespace {} and saved it to {}",
+                *UUID_NAMESPACE,
+                config_path.display()
+            )),
+            Ok(None) => {}
+            Err(e) => reporter.warn(format!(
+                "Could not save uuid_namespace to the config file, node IDs will differ \
+                 between runs: {}",
+                e
+            )),
+        }
+    }
+
+    let highlight_sorter = sort::sorter(SETTINGS.highlight_sort);
+    reporter.info("Processing papers and generating/updating org files...");
     for paper in &papers {
-        let current_highlights = highlights_map.get(&paper.id).cloned().unwrap_or_default();
+        let mut current_highlights = if !paper.has_highlights || cli.no_highlights {
+            Vec::new()
+        } else if SETTINGS.stream_highlights {
+            backend.paper_highlights(&paper.id)?
+        } else {
+            highlights_map.remove(&paper.id).unwrap_or_default()
+        };
+        if SETTINGS.deduplicate_highlights {
+            current_highlights = deduplicate_highlights(&current_highlights);
+        }
+        highlight_sorter.sort(&mut current_highlights);
 
-        let highlight_content_str = generate_highlight_content(&current_highlights, &tera)?;
+        let existing_file = existing_refs.get(&paper.roam_ref);
+        // Notes are only rendered in new files
+        let child_notes =
+            if SETTINGS.include_child_notes && existing_file.is_none() && paper.note_count > 0 {
+                child_notes_org(&backend.child_notes(&paper.id)?)
+            } else {
+                Vec::new()
+            };
+        let highlights_template = match existing_file {
+            Some(_) => SETTINGS.update_file_template(),
+            None => SETTINGS.highlights_template(),
+        };
+        let mut omitted_highlights = 0;
+        if let Some(max_highlights) = SETTINGS.max_highlights_per_paper {
+            omitted_highlights = current_highlights.len().saturating_sub(max_highlights);
+            current_highlights.truncate(max_highlights);
+        }
+        let mut omitted_reason = "highlight limit";
+        if let (Some(max_bytes), false) =
+            (SETTINGS.max_file_size_bytes, current_highlights.is_empty())
+        {
+            let highlight_count = current_highlights.len();
+            // Errors reading or rendering the whole file are reported when it is written
+            let file_size = |count: usize| -> Result<u64, tera::Error> {
+                let section = generate_highlight_content(
+                    &current_highlights[..count],
+                    omitted_notice(
+                        omitted_highlights + highlight_count - count,
+                        "file size limit",
+                    )
+                    .as_deref(),
+                    &tera,
+                    highlights_template,
+                )?;
+                let size = match existing_file {
+                    Some(filename) => match get_edited_content(filename, &section) {
+                        Ok(Some(content)) => content.len(),
+                        Ok(None) => read_org_file(filename).map_or(0, |content| content.len()),
+                        Err(_) => 0,
+                    },
+                    None => generate_file_content(paper, &section, &child_notes, &tera)
+                        .map_or(0, |content| content.len()),
+                };
+                Ok(size as u64)
+            };
+            let fitting = highlights_fitting_size(highlight_count, max_bytes, file_size)?;
+            if fitting < highlight_count {
+                reporter.warn(format!(
+                    "Omitting {} of the highlights of {:?} to keep its file under {} bytes",
+                    highlight_count - fitting,
+                    paper.title,
+                    max_bytes
+                ));
+                omitted_highlights += highlight_count - fitting;
+                omitted_reason = "file size limit";
+                current_highlights.truncate(fitting);
+            }
+        }
+        let highlight_content_str = generate_highlight_content(
+            &current_highlights,
+            omitted_notice(omitted_highlights, omitted_reason).as_deref(),
+            &tera,
+            highlights_template,
+        )?;
 
-        if let Some(filename) = existing_refs.get(&paper.roam_ref) {
-            match edit_file(filename, paper, &highlight_content_str) {
-                Ok(true) => {
-                    println!("Edited file: {}", filename);
+        if cli.compare_db_to_files {
+            let Some(filename) = existing_file else {
+                continue;
+            };
+            match existing_highlights_section(filename) {
+                Ok(section) => {
+                    files_compared += 1;
+                    if compare::section_hash(&section)
+                        != compare::section_hash(&highlight_content_str)
+                    {
+                        reporter.info(format!(
+                            "Out of sync: {} ({} differing highlights)",
+                            filename,
+                            compare::differing_highlights(&highlight_content_str, &section)
+                        ));
+                        files_out_of_date += 1;
+                    }
+                }
+                Err(e) => report_error(
+                    &reporter,
+                    &mut run_errors,
+                    paper,
+                    format!("Error reading file {}: {}", filename, e),
+                    Some(filename),
+                ),
+            }
+            continue;
+        }
+
+        if let Some(filename) = existing_file {
+            // Without highlights, there is nothing to update in existing files
+            if cli.only_new || cli.no_highlights {
+                continue;
+            }
+            let new_content = match get_edited_content(filename, &highlight_content_str) {
+                Ok(Some(content)) => content,
+                Ok(None) => continue,
+                Err(e) => {
+                    report_error(
+                        &reporter,
+                        &mut run_errors,
+                        paper,
+                        format!("Error editing file {}: {}", filename, e),
+                        Some(filename),
+                    );
+                    continue;
+                }
+            };
+            if cli.assert_no_edits {
+                let old_content = read_org_file(filename).unwrap_or_default();
+                reporter.info(format!(
+                    "File is out of date: {}\n{}",
+                    filename,
+                    diff::line_diff(&old_content, &new_content, 2)
+                ));
+                files_out_of_date += 1;
+                continue;
+            }
+            // Before any prompt, which --yes would answer
+            if cli.dry_run {
+                match report_dry_run_file(
+                    &reporter,
+                    preview_dir.as_deref(),
+                    org_roam_dir,
+                    FileAction::Edit,
+                    filename,
+                    &new_content,
+                ) {
+                    Ok(()) => files_would_edit += 1,
+                    Err(e) => report_error(
+                        &reporter,
+                        &mut run_errors,
+                        paper,
+                        format!("Error writing the preview of {}: {}", filename, e),
+                        Some(filename),
+                    ),
+                }
+                continue;
+            }
+            match prompter.confirm(&paper.title, FileAction::Edit, filename)? {
+                Decision::Proceed => {}
+                Decision::Skip => {
+                    reporter.emit(&Event::FileSkipped {
+                        path: filename,
+                        paper_id: &paper.id,
+                        title: &paper.title,
+                    });
+                    files_skipped += 1;
+                    continue;
+                }
+                Decision::Quit => break,
+            }
+            match write_org_file(filename, &new_content) {
+                Ok(_) => {
+                    reporter.emit(&Event::FileEdited {
+                        path: filename,
+                        paper_id: &paper.id,
+                        title: &paper.title,
+                    });
                     files_edited += 1;
                 }
-                Ok(false) => {}
-                Err(e) => eprintln!("Error editing file {}: {}", filename, e),
+                Err(e) => {
+                    report_error(
+                        &reporter,
+                        &mut run_errors,
+                        paper,
+                        format!("Error editing file {}: {}", filename, e),
+                        Some(filename),
+                    );
+                }
             }
         } else {
-            let filename = if duplicate_titles.contains(&paper.title) {
+            let entry_dir = if SETTINGS.organize_by_collection {
+                org_roam_dir.join(
+                    paper
+                        .collection_path
+                        .as_deref()
+                        .unwrap_or(UNCATEGORIZED_DIR),
+                )
+            } else {
+                org_roam_dir.to_path_buf()
+            };
+            let author_prefix = paper.filename_author_prefix();
+            let filename = if duplicate_titles
+                .iter()
+                .any(|title| title == paper.filename_title())
+            {
                 get_new_entry_filename(
-                    org_roam_dir,
-                    &paper.title,
+                    &entry_dir,
+                    paper.filename_title(),
                     if paper.has_url {
                         Some(&paper.source_url)
                     } else {
                         None
                     },
+                    author_prefix.as_deref(),
                 )
             } else {
-                get_new_entry_filename(org_roam_dir, &paper.title, None)
+                get_new_entry_filename(
+                    &entry_dir,
+                    paper.filename_title(),
+                    None,
+                    author_prefix.as_deref(),
+                )
             };
 
-            match generate_file_content(paper, &highlight_content_str, &tera) {
-                Ok(content) => match fs::write(&filename, &content) {
+            if cli.assert_no_edits {
+                reporter.info(format!(
+                    "No file for paper {:?}, would create: {}",
+                    paper.title, filename
+                ));
+                files_out_of_date += 1;
+                continue;
+            }
+            if cli.dry_run {
+                let previewed =
+                    generate_file_content(paper, &highlight_content_str, &child_notes, &tera)
+                        .map_err(|e| format!("Error generating content for {}: {}", paper.title, e))
+                        .and_then(|content| {
+                            report_dry_run_file(
+                                &reporter,
+                                preview_dir.as_deref(),
+                                org_roam_dir,
+                                FileAction::Create,
+                                &filename,
+                                &content,
+                            )
+                            .map_err(|e| {
+                                format!("Error writing the preview of {}: {}", filename, e)
+                            })
+                        });
+                match previewed {
+                    Ok(()) => files_would_create += 1,
+                    Err(message) => report_error(&reporter, &mut run_errors, paper, message, None),
+                }
+                continue;
+            }
+            match prompter.confirm(&paper.title, FileAction::Create, &filename)? {
+                Decision::Proceed => {}
+                Decision::Skip => {
+                    reporter.emit(&Event::FileSkipped {
+                        path: &filename,
+                        paper_id: &paper.id,
+                        title: &paper.title,
+                    });
+                    files_skipped += 1;
+                    continue;
+                }
+                Decision::Quit => break,
+            }
+            match generate_file_content(paper, &highlight_content_str, &child_notes, &tera) {
+                Ok(content) => match fs::create_dir_all(&entry_dir)
+                    .and_then(|_| write_org_file(&filename, &content))
+                {
                     Ok(_) => {
-                        println!("Created file: {}", filename);
+                        reporter.emit(&Event::FileCreated {
+                            path: &filename,
+                            paper_id: &paper.id,
+                            title: &paper.title,
+                        });
                         files_created += 1;
                     }
-                    Err(e) => eprintln!("Error writing file {}: {}", filename, e),
+                    Err(e) => {
+                        report_error(
+                            &reporter,
+                            &mut run_errors,
+                            paper,
+                            format!("Error writing file {}: {}", filename, e),
+                            Some(&filename),
+                        );
+                    }
                 },
-                Err(e) => eprintln!("Error generating content for {}: {}", paper.title, e),
+                Err(e) => {
+                    report_error(
+                        &reporter,
+                        &mut run_errors,
+                        paper,
+                        format!("Error generating content for {}: {}", paper.title, e),
+                        None,
+                    );
+                }
             }
         }
     }
 
-    println!("\n--- Summary ---");
-    println!("Files created: {}", files_created);
-    println!("Files edited: {}", files_edited);
-    let duration = start_time.elapsed();
-    println!("Total time taken: {:?}", duration);
+    if cli.dry_run {
+        reporter.info(format!(
+            "Dry run: {} files would be created and {} edited.",
+            files_would_create, files_would_edit
+        ));
+        if let (Some(preview_dir), true) = (&preview_dir, files_would_create + files_would_edit > 0)
+        {
+            reporter.info(preview_diff_hint(preview_dir, org_roam_dir));
+        }
+    }
+
+    if let (Some(emacs_command), true, false) = (
+        &SETTINGS.emacs_command,
+        files_created + files_edited > 0,
+        cli.skip_emacs_eval,
+    ) {
+        run_emacs_eval(emacs_command, &reporter);
+    }
 
-    match fs::remove_file(&temp_db_path) {
-        Ok(_) => println!("Cleaned up temporary database: {}", temp_db_path.display()),
-        Err(e) => eprintln!(
-            "Warning: Failed to clean up temporary database {}: {}",
-            temp_db_path.display(),
-            e
-        ),
+    reporter.emit(&Event::Summary {
+        files_created,
+        files_edited,
+        files_skipped,
+        errors: run_errors.len(),
+        duration_ms: start_time.elapsed().as_millis(),
+    });
+
+    if let Some(summary_output) = &SETTINGS.summary_output {
+        let summary = RunSummary {
+            run_at: run_at.to_rfc3339(),
+            duration_ms: start_time.elapsed().as_millis(),
+            papers_found: papers.len(),
+            papers_created: files_created,
+            papers_edited: files_edited,
+            papers_skipped: files_skipped,
+            errors: run_errors,
+            zotero_db_mtime: zotero_db_mtime(),
+        };
+        if let Err(e) = output::write_summary_file(summary_output, &summary) {
+            eprintln!(
+                "Failed to write run summary to {}: {}",
+                summary_output.display(),
+                e
+            );
+        }
+    }
+
+    drop(backend);
+    drop(temp_db);
+
+    if cli.assert_no_edits {
+        if files_out_of_date > 0 {
+            eprintln!("{} org files are not up to date", files_out_of_date);
+            // process::exit skips destructors
+            drop(lock);
+            std::process::exit(1);
+        }
+        reporter.info("All org files are up to date");
+    }
+    if cli.compare_db_to_files {
+        if files_out_of_date > 0 {
+            eprintln!(
+                "{} of {} org files are out of sync with the Zotero database",
+                files_out_of_date, files_compared
+            );
+            drop(lock);
+            std::process::exit(1);
+        }
+        reporter.info("All org files are in sync with the Zotero database");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_helpers::{create_test_db, TestPaper};
+
+    /// The columns `map_row_to_paper` reads, as named in `papers_sql`.
+    const PAPER_COLUMNS: [&str; 21] = [
+        "paperID",
+        "title",
+        "url",
+        "dateAdded",
+        "group_id",
+        "publication_date",
+        "item_type",
+        "attachment_path",
+        "attachment_key",
+        "note_count",
+        "short_title",
+        "attachment_content_type",
+        "series",
+        "series_number",
+        "pages",
+        "page_count",
+        "access_date",
+        "item_key",
+        "volume_number",
+        "number_of_volumes",
+        "has_pdf",
+    ];
+
+    /// Maps a row of `values`, in the order of `PAPER_COLUMNS`, selected from
+    /// an in-memory database.
+    fn map_values_to_paper(values: &[Value]) -> Result<Paper> {
+        let conn = Connection::open_in_memory().unwrap();
+        let columns: Vec<String> = PAPER_COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(i, column)| format!("?{} AS {}", i + 1, column))
+            .collect();
+        conn.query_row(
+            &format!("SELECT {}", columns.join(", ")),
+            params_from_iter(values),
+            map_row_to_paper,
+        )
+    }
+
+    fn any_value() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<i64>().prop_map(Value::Integer),
+            any::<f64>().prop_map(Value::Real),
+            any::<String>().prop_map(Value::Text),
+        ]
+    }
+
+    fn any_text() -> BoxedStrategy<Value> {
+        any::<String>().prop_map(Value::from).boxed()
+    }
+
+    fn any_optional_text() -> BoxedStrategy<Value> {
+        proptest::option::of(any::<String>())
+            .prop_map(Value::from)
+            .boxed()
+    }
+
+    /// Values of the types Zotero stores in each column of `PAPER_COLUMNS`.
+    fn paper_row() -> Vec<BoxedStrategy<Value>> {
+        let date = (1900i32..2100, 1u32..=12, 1u32..=28)
+            .prop_map(|(year, month, day)| format!("{:04}-{:02}-{:02}", year, month, day));
+        let url = prop_oneof![
+            Just(String::new()),
+            any::<String>().prop_map(|path| format!("https://example.com/{}", path)),
+        ];
+        vec![
+            any::<i64>().prop_map(Value::Integer).boxed(),
+            any_text(),
+            proptest::option::of(url).prop_map(Value::from).boxed(),
+            prop_oneof![date, any::<String>()]
+                .prop_map(Value::from)
+                .boxed(),
+            proptest::option::of(any::<i64>())
+                .prop_map(Value::from)
+                .boxed(),
+            any_optional_text(),
+            any_text(),
+            any_optional_text(),
+            any_optional_text(),
+            (0i64..1000).prop_map(Value::Integer).boxed(),
+            any_optional_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any_text(),
+            any_optional_text(),
+            any_optional_text(),
+            any::<bool>().prop_map(Value::from).boxed(),
+        ]
+    }
+
+    proptest! {
+        // Values of the wrong type are errors, not panics
+        #[test]
+        fn map_row_to_paper_never_panics(
+            values in proptest::collection::vec(any_value(), PAPER_COLUMNS.len())
+        ) {
+            let _ = map_values_to_paper(&values);
+        }
+
+        #[test]
+        fn map_row_to_paper_maps_zotero_rows(values in paper_row()) {
+            let paper = map_values_to_paper(&values);
+            prop_assert!(paper.is_ok(), "{:?}", paper);
+            let paper = paper.unwrap();
+            prop_assert!(!paper.id.is_empty());
+            // The date the paper was added, or now when it doesn't parse
+            let Value::Text(date_added) = &values[3] else {
+                unreachable!()
+            };
+            match NaiveDate::parse_from_str(date_added, "%Y-%m-%d") {
+                Ok(date) => prop_assert_eq!(paper.saved_at.date_naive(), date),
+                Err(_) => prop_assert!(Utc::now() - paper.saved_at < chrono::Duration::minutes(1)),
+            }
+            prop_assert!(
+                paper.roam_ref.starts_with("http") || paper.roam_ref.starts_with("@zotero_"),
+                "{}",
+                paper.roam_ref
+            );
+        }
+    }
+
+    #[test]
+    fn query_papers_reads_test_db() {
+        let conn = create_test_db(&[
+            TestPaper::new("Attention Is All You Need")
+                .url("https://arxiv.org/abs/1706.03762")
+                .date("2017-06-12")
+                .author("Ashish", "Vaswani")
+                .author("Noam", "Shazeer")
+                .tag("transformers")
+                .collection("ML"),
+            TestPaper::new("A Book").item_type("book").key("BOOK0001"),
+        ]);
+
+        let papers = query_papers(&conn, None).unwrap();
+
+        assert_eq!(papers.len(), 2);
+        let article = &papers[0];
+        assert_eq!(article.title, "Attention Is All You Need");
+        assert_eq!(article.roam_ref, "https://arxiv.org/abs/1706.03762");
+        assert_eq!(article.publication_year, Some(2017));
+        assert_eq!(article.author, "Ashish Vaswani, Noam Shazeer");
+        assert_eq!(article.tags, ["transformers"]);
+        assert_eq!(article.collection_path.as_deref(), Some("ML"));
+        assert!(article.has_pdf);
+        let book = &papers[1];
+        assert_eq!(book.item_type, "book");
+        assert_eq!(book.item_key, "BOOK0001");
+        assert_eq!(book.roam_ref, format!("@zotero_{}", book.id));
+    }
+
+    #[test]
+    fn query_papers_returns_papers_with_attachments_once() {
+        let conn = create_test_db(&[
+            TestPaper::new("No attachment").no_attachment(),
+            TestPaper::new("One attachment"),
+            TestPaper::new("Two attachments")
+                .attachment("storage:snapshot.html", "text/html")
+                .extra_attachment("storage:paper.pdf", PDF_CONTENT_TYPE),
+        ]);
+
+        let papers = query_papers(&conn, None).unwrap();
+
+        let titles: Vec<&str> = papers.iter().map(|paper| paper.title.as_str()).collect();
+        assert_eq!(titles, ["One attachment", "Two attachments"]);
+        // The PDF is preferred over the snapshot added before it
+        assert_eq!(
+            papers[1].attachment_content_type.as_deref(),
+            Some(PDF_CONTENT_TYPE)
+        );
+    }
+
+    #[test]
+    fn trashed_items_are_skipped() {
+        let conn = create_test_db(&[
+            TestPaper::new("Trashed paper").trashed(),
+            TestPaper::new("Trashed attachment only")
+                .no_attachment()
+                .trashed_attachment("storage:paper.pdf", PDF_CONTENT_TYPE),
+            TestPaper::new("Some trashed children")
+                .attachment("storage:snapshot.html", "text/html")
+                .trashed_attachment("storage:paper.pdf", PDF_CONTENT_TYPE)
+                .note("<p>Kept</p>")
+                .trashed_note("<p>Trashed</p>"),
+        ]);
+
+        let papers = query_papers(&conn, None).unwrap();
+
+        assert_eq!(papers.len(), 1);
+        let paper = &papers[0];
+        assert_eq!(paper.title, "Some trashed children");
+        assert_eq!(paper.attachment_content_type.as_deref(), Some("text/html"));
+        assert!(!paper.has_pdf);
+        assert_eq!(paper.note_count, 1);
+        let notes = query_child_notes_for_paper(&conn, paper.id.parse().unwrap()).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].html, "<p>Kept</p>");
+    }
+
+    #[test]
+    fn detect_zotero_schema_version_reads_version_table_or_user_version() {
+        let conn = create_test_db(&[]);
+        conn.execute_batch("PRAGMA user_version = 113").unwrap();
+        assert_eq!(detect_zotero_schema_version(&conn).unwrap(), 113);
+
+        conn.execute_batch(
+            "CREATE TABLE version (schema TEXT PRIMARY KEY, version INT NOT NULL);
+             INSERT INTO version VALUES ('globalSchema', 37), ('userdata', 120);",
+        )
+        .unwrap();
+        assert_eq!(detect_zotero_schema_version(&conn).unwrap(), 120);
+    }
+
+    #[test]
+    fn write_preview_mirrors_org_roam_dir() {
+        let preview_dir = env::temp_dir().join(format!("org-zotero-preview-{}", Uuid::new_v4()));
+        let org_roam_dir = Path::new("/home/user/org/roam");
+
+        let nested = write_preview(
+            &preview_dir,
+            org_roam_dir,
+            "/home/user/org/roam/ML/paper.org",
+            "content",
+        )
+        .unwrap();
+        let outside =
+            write_preview(&preview_dir, org_roam_dir, "/elsewhere/other.org", "other").unwrap();
+
+        assert_eq!(nested, preview_dir.join("ML/paper.org"));
+        assert_eq!(read_org_file(&nested).unwrap(), "content");
+        assert_eq!(outside, preview_dir.join("other.org"));
+        fs::remove_dir_all(&preview_dir).unwrap();
+    }
+
+    #[test]
+    fn query_attachment_annotations_count_counts_one_paper() {
+        let conn = create_test_db(&[
+            TestPaper::new("Annotated")
+                .annotation(Some("A highlight"), None)
+                .annotation(None, Some("A sticky note"))
+                .annotation(Some(" "), None),
+            TestPaper::new("Other").annotation(Some("Another highlight"), None),
+        ]);
+        let papers = query_papers(&conn, None).unwrap();
+
+        let count =
+            query_attachment_annotations_count(&conn, papers[0].id.parse().unwrap()).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(
+            query_highlight_counts(&conn).unwrap().get(&papers[0].id),
+            Some(&count)
+        );
+    }
+
+    #[test]
+    fn paper_builder_derives_fields_from_given_settings() {
+        let mut settings = test_helpers::test_settings();
+        settings.authors_last_separator = Some(" and ".to_string());
+        settings
+            .tag_mapping
+            .insert("machine learning".to_string(), "ml".to_string());
+
+        let paper = PaperBuilder::new()
+            .author("Ada", "Lovelace", None)
+            .author("Charles", "Babbage", None)
+            .tag("machine learning")
+            .tag("_tablet")
+            .build(&settings);
+
+        assert_eq!(paper.author, "Ada Lovelace and Charles Babbage");
+        assert_eq!(paper.tags, ["machine learning", "_tablet"]);
+        assert_eq!(paper.tags_filtered, ["ml"]);
+    }
+
+    #[test]
+    fn format_author_list_keeps_distinct_creators_with_the_same_name() {
+        let settings = test_helpers::test_settings();
+        let creator = |id, first_name, last_name| Author {
+            creator_id: Some(id),
+            ..Author::new(first_name, last_name)
+        };
+
+        let authors = [
+            creator(1, "Wei", "Zhang"),
+            creator(2, "Wei", "Zhang"),
+            // The first creator again, in another role
+            creator(1, "Wei", "Zhang"),
+        ];
+
+        assert_eq!(
+            format_author_list(&authors, &settings),
+            format!("Wei Zhang{}Wei Zhang", settings.authors_separator)
+        );
+    }
+}
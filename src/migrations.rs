@@ -0,0 +1,136 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A version of org-zotero-rust, as in `Cargo.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().trim_start_matches('v').split('.').collect();
+        if parts.len() != 3 {
+            return Err(format!(
+                "invalid version {:?}, expected MAJOR.MINOR.PATCH",
+                s
+            ));
+        }
+        let parse = |part: &str| {
+            part.parse::<u32>()
+                .map_err(|_| format!("invalid version {:?}, expected MAJOR.MINOR.PATCH", s))
+        };
+        Ok(Version::new(
+            parse(parts[0])?,
+            parse(parts[1])?,
+            parse(parts[2])?,
+        ))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Rewrites an org file generated by version `from` into the format of version `to`.
+pub struct Migration {
+    pub from: Version,
+    pub to: Version,
+    pub apply: fn(&str) -> String,
+}
+
+/// All known migrations, each going from one release's file format to the next one.
+/// A migration is only needed when the default templates change in a way that
+/// `edit_file` cannot fix by itself, e.g. a renamed property or a new heading level.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: Version::new(0, 1, 0),
+    to: Version::new(0, 2, 0),
+    apply: zotero_library_links,
+}];
+
+/// 0.2.0 links items of the personal library with `zotero://select/library/items/KEY`
+/// instead of `zotero://select/items/0_KEY`. Links to group items, which held the
+/// library ID rather than the group ID, are left unchanged: the group ID can't
+/// be told from the file.
+fn zotero_library_links(content: &str) -> String {
+    content.replace("zotero://select/items/0_", "zotero://select/library/items/")
+}
+
+/// Returns the chain of migrations leading from `from` to `to`.
+pub fn migrations_between(from: Version, to: Version) -> Result<Vec<&'static Migration>, String> {
+    if to < from {
+        return Err(format!("cannot migrate backwards from {} to {}", from, to));
+    }
+    let mut chain = Vec::new();
+    let mut current = from;
+    while current < to {
+        match MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == current)
+        {
+            Some(migration) if migration.to <= to => {
+                chain.push(migration);
+                current = migration.to;
+            }
+            // No format change was released from `current` on
+            _ => break,
+        }
+    }
+    Ok(chain)
+}
+
+pub fn apply_migrations(content: &str, migrations: &[&Migration]) -> String {
+    migrations
+        .iter()
+        .fold(content.to_string(), |content, migration| {
+            (migration.apply)(&content)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_zotero_links_from_0_1_0() {
+        let content = "\
+:PROPERTIES:
+:ID: 6f0f1a52-8d2b-4b8a-9a3e-3f6b8c1e2d4a
+:ROAM_REFS: @zotero_42
+:END:
+#+TITLE: A Paper
+
+- author: Ada Lovelace
+- zotero link: zotero://select/items/0_ABCD2345
+- group link: zotero://select/items/3_EFGH6789
+";
+        let chain = migrations_between(Version::new(0, 1, 0), Version::new(0, 2, 0)).unwrap();
+
+        let migrated = apply_migrations(content, &chain);
+
+        assert_eq!(
+            migrated,
+            content.replace(
+                "zotero://select/items/0_ABCD2345",
+                "zotero://select/library/items/ABCD2345"
+            )
+        );
+        assert_eq!(apply_migrations(&migrated, &chain), migrated);
+    }
+}
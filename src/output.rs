@@ -0,0 +1,203 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use console::{style, Term};
+use serde::Serialize;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable progress messages
+    #[default]
+    Text,
+    /// One JSON object per event, for jq or log aggregators
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color output written to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    /// Always color output, even when piped
+    Always,
+    /// Never color output
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled_for(self, term: &Term) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            // https://no-color.org
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && term.is_term()
+            }
+        }
+    }
+
+    /// Enables or disables colors for stdout and stderr.
+    pub fn apply(self) {
+        console::set_colors_enabled(self.enabled_for(&Term::stdout()));
+        console::set_colors_enabled_stderr(self.enabled_for(&Term::stderr()));
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    FileCreated {
+        path: &'a str,
+        paper_id: &'a str,
+        title: &'a str,
+    },
+    FileEdited {
+        path: &'a str,
+        paper_id: &'a str,
+        title: &'a str,
+    },
+    FileSkipped {
+        path: &'a str,
+        paper_id: &'a str,
+        title: &'a str,
+    },
+    Error {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        paper_id: Option<&'a str>,
+    },
+    Summary {
+        files_created: usize,
+        files_edited: usize,
+        files_skipped: usize,
+        errors: usize,
+        duration_ms: u128,
+    },
+}
+
+/// Written to `summary_output` at the end of a run, for monitoring scripts.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_at: String,
+    pub duration_ms: u128,
+    pub papers_found: usize,
+    pub papers_created: usize,
+    pub papers_edited: usize,
+    pub papers_skipped: usize,
+    pub errors: Vec<RunError>,
+    pub zotero_db_mtime: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunError {
+    pub paper_id: String,
+    pub title: String,
+    pub error_message: String,
+}
+
+pub fn write_summary_file(path: &Path, summary: &RunSummary) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(summary)?;
+    fs::write(path, json + "\n")
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    timestamp: String,
+}
+
+/// Writes progress to stdout, either as human-readable text or as JSON lines.
+#[derive(Debug)]
+pub struct Reporter {
+    format: OutputFormat,
+    // Suppresses `info` and `warn`, for modes that print data to stdout
+    quiet: bool,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat) -> Self {
+        Reporter {
+            format,
+            quiet: false,
+        }
+    }
+
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Prints a human-readable message. Suppressed in JSON-lines mode.
+    pub fn info(&self, message: impl Display) {
+        if self.format == OutputFormat::Text && !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    /// Like `info`, highlighted as a warning.
+    pub fn warn(&self, message: impl Display) {
+        if self.format == OutputFormat::Text && !self.quiet {
+            println!("{}", style(message).yellow());
+        }
+    }
+
+    pub fn emit(&self, event: &Event) {
+        match self.format {
+            OutputFormat::Text => print_text(event),
+            OutputFormat::Jsonl => {
+                let record = Record {
+                    event,
+                    timestamp: Utc::now().to_rfc3339(),
+                };
+                match serde_json::to_string(&record) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => eprintln!("Failed to serialize event {:?}: {}", event, e),
+                }
+            }
+        }
+    }
+}
+
+fn print_text(event: &Event) {
+    match event {
+        Event::FileCreated { path, .. } => println!("{} {}", style("Created file:").green(), path),
+        Event::FileEdited { path, .. } => println!("{} {}", style("Edited file:").yellow(), path),
+        Event::FileSkipped { path, .. } => println!("Skipped file: {}", path),
+        Event::Error { message, .. } => eprintln!("{}", style(message).for_stderr().red()),
+        Event::Summary {
+            files_created,
+            files_edited,
+            files_skipped,
+            errors,
+            duration_ms,
+        } => {
+            println!("\n--- Summary ---");
+            println!(
+                "{} {}",
+                style("Files created:").green(),
+                style(files_created).bold()
+            );
+            println!(
+                "{} {}",
+                style("Files edited:").yellow(),
+                style(files_edited).bold()
+            );
+            if *files_skipped > 0 {
+                println!("Files skipped: {}", style(files_skipped).bold());
+            }
+            if *errors > 0 {
+                println!("{} {}", style("Errors:").red(), style(errors).bold());
+            }
+            let duration = Duration::from_millis(*duration_ms as u64);
+            println!("Total time taken: {:?}", duration);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use rusqlite::{Connection, OpenFlags, Result};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+/// A small pool of SQLite connections to one database file.
+///
+/// `Connection` is `Send` but not `Sync`, so threads cannot share one. The pool
+/// is `Sync` and hands out one connection per caller, opening new ones lazily
+/// up to `max_size` and blocking when all of them are in use.
+///
+/// Connections are opened with `PRAGMA query_only = ON`, so that nothing can
+/// write to the database by accident, followed by the pragmas of `with_pragmas`.
+pub struct ConnectionPool {
+    path: PathBuf,
+    flags: OpenFlags,
+    max_size: usize,
+    // (name, value), e.g. ("cache_size", "10000")
+    pragmas: Vec<(String, String)>,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    // Connections currently open, idle or handed out
+    open: usize,
+}
+
+impl ConnectionPool {
+    pub fn new(path: &Path, flags: OpenFlags, max_size: usize) -> Self {
+        ConnectionPool {
+            path: path.to_path_buf(),
+            flags,
+            max_size: max_size.max(1),
+            pragmas: Vec::new(),
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Runs `PRAGMA <name> = <value>` on each new connection. Names and values
+    /// are not escaped and must be validated beforehand.
+    pub fn with_pragmas(mut self, pragmas: Vec<(String, String)>) -> Self {
+        self.pragmas = pragmas;
+        self
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open_with_flags(&self.path, self.flags)?;
+        conn.execute_batch("PRAGMA query_only = ON")?;
+        for (name, value) in &self.pragmas {
+            conn.execute_batch(&format!("PRAGMA {} = {}", name, value))?;
+        }
+        Ok(conn)
+    }
+
+    pub fn get(&self) -> Result<PoolGuard<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(PoolGuard {
+                    pool: self,
+                    conn: Some(conn),
+                });
+            }
+            if state.open < self.max_size {
+                state.open += 1;
+                break;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+        drop(state);
+
+        // Open outside of the lock, giving the slot back if it fails
+        match self.open() {
+            Ok(conn) => Ok(PoolGuard {
+                pool: self,
+                conn: Some(conn),
+            }),
+            Err(e) => {
+                self.state.lock().unwrap().open -= 1;
+                self.available.notify_one();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A connection borrowed from a `ConnectionPool`, returned to it when dropped.
+pub struct PoolGuard<'a> {
+    pool: &'a ConnectionPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PoolGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PoolGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.state.lock().unwrap().idle.push(conn);
+            self.pool.available.notify_one();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAction {
+    Create,
+    Edit,
+}
+
+impl fmt::Display for FileAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileAction::Create => write!(f, "create"),
+            FileAction::Edit => write!(f, "edit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Proceed,
+    Skip,
+    Quit,
+}
+
+/// Asks the user before each file operation when running with `--interactive`,
+/// unless `--yes` was given.
+#[derive(Debug)]
+pub struct Prompter {
+    enabled: bool,
+    assume_yes: bool,
+    // Set by the "s" answer: all later creations are skipped without asking,
+    // while edits are still prompted for.
+    skip_all_new: bool,
+}
+
+impl Prompter {
+    pub fn new(enabled: bool, assume_yes: bool) -> Self {
+        Prompter {
+            enabled,
+            assume_yes,
+            skip_all_new: false,
+        }
+    }
+
+    pub fn confirm(
+        &mut self,
+        title: &str,
+        action: FileAction,
+        filename: &str,
+    ) -> io::Result<Decision> {
+        if !self.enabled || self.assume_yes {
+            return Ok(Decision::Proceed);
+        }
+        if action == FileAction::Create && self.skip_all_new {
+            return Ok(Decision::Skip);
+        }
+
+        println!("\n{}", title);
+        println!("  action: {}", action);
+        println!("  file: {}", filename);
+        loop {
+            print!("Proceed? [y/n/s/q] (yes / no / skip all new / quit) ");
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                // stdin was closed, there is nobody left to ask
+                return Ok(Decision::Quit);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(Decision::Proceed),
+                "n" | "no" => return Ok(Decision::Skip),
+                "s" => {
+                    self.skip_all_new = true;
+                    return Ok(Decision::Skip);
+                }
+                "q" | "quit" => return Ok(Decision::Quit),
+                _ => println!("Please answer y, n, s or q."),
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use rusqlite::Connection;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{prepare_sql, table_has_column};
+
+/// File in the org-roam directory recording the Zotero schema version that was
+/// last validated. The schema is validated automatically when it is missing.
+pub const STATE_FILENAME: &str = ".org-zotero.state";
+
+/// Tables read from the Zotero database, with the columns the queries rely on.
+const EXPECTED_SCHEMA: &[(&str, &[&str])] = &[
+    ("items", &["itemID", "itemTypeID", "key", "dateAdded"]),
+    ("itemData", &["itemID", "fieldID", "valueID"]),
+    ("itemDataValues", &["valueID", "value"]),
+    (
+        "itemAttachments",
+        &["itemID", "parentItemID", "contentType", "path"],
+    ),
+    (
+        "itemAnnotations",
+        &[
+            "itemID",
+            "parentItemID",
+            "text",
+            "comment",
+            "color",
+            "sortIndex",
+        ],
+    ),
+    (
+        "creators",
+        &["creatorID", "firstName", "lastName", "fieldMode"],
+    ),
+    ("itemCreators", &["itemID", "creatorID", "orderIndex"]),
+    ("itemTypes", &["itemTypeID", "typeName"]),
+    ("tags", &["tagID", "name"]),
+    ("itemTags", &["itemID", "tagID"]),
+    ("collections", &["collectionID", "collectionName"]),
+    ("collectionItems", &["collectionID", "itemID"]),
+    ("deletedItems", &["itemID"]),
+];
+
+/// Returns the expected tables and columns missing from the database,
+/// as e.g. `table itemAnnotations` or `column items.key`.
+pub fn missing_schema_items(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut missing = Vec::new();
+    for (table, columns) in EXPECTED_SCHEMA {
+        let has_table: bool = prepare_sql(
+            conn,
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        )?
+        .query_row([table], |row| row.get(0))?;
+        if !has_table {
+            missing.push(format!("table {}", table));
+            continue;
+        }
+        for column in columns.iter() {
+            if !table_has_column(conn, table, column)? {
+                missing.push(format!("column {}.{}", table, column));
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// Whether a previous run already validated the schema.
+pub fn is_validated(org_roam_dir: &Path) -> bool {
+    org_roam_dir.join(STATE_FILENAME).is_file()
+}
+
+/// Records that the schema of the given version was validated.
+pub fn mark_validated(org_roam_dir: &Path, schema_version: u32) -> io::Result<()> {
+    fs::write(
+        org_roam_dir.join(STATE_FILENAME),
+        format!("validated_schema_version = {}\n", schema_version),
+    )
+}
@@ -1,41 +1,666 @@
 use config::{Config, File};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::path::PathBuf;
+use regex::Regex;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Settings {
     pub org_roam_dir: PathBuf,
     pub templates_dir: PathBuf,
+    // Detected from Zotero's profiles.ini when not set
+    #[serde(default)]
     pub zotero_db_path: PathBuf,
+    // Read from the local API of a running Zotero 7 instead of the database
+    pub zotero_local_api_port: Option<u16>,
+    // Defaults to the `storage` directory next to the Zotero database
+    pub zotero_storage_dir: Option<PathBuf>,
+    // Existing refs are read from org-roam's database when set, instead of scanning files
+    pub org_roam_db_path: Option<PathBuf>,
+    // With --dry-run, the files that would change are written there for inspection
+    pub dry_run_output_dir: Option<PathBuf>,
+    // Run with --eval emacs_eval_after_sync when files were created or edited, e.g.
+    // "emacsclient", so that org-roam's database is updated
+    pub emacs_command: Option<String>,
+    // Defaults to "(org-roam-db-sync)"
+    pub emacs_eval_after_sync: Option<String>,
+    // JSON summary written at the end of each run
+    pub summary_output: Option<PathBuf>,
+    #[serde(default)]
+    pub file_encoding: FileEncoding,
+    // Unix permissions set on the org files written, e.g. "0o644"
+    #[serde(default, deserialize_with = "deserialize_file_permissions")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<String>"))]
+    pub file_permissions: Option<u32>,
+    #[serde(default)]
+    pub deduplicate_highlights: bool,
+    // Render the child notes of papers, converted from HTML, in new files
+    #[serde(default)]
+    pub include_child_notes: bool,
+    // Query highlights paper by paper instead of all at once, to use less memory
+    #[serde(default = "default_stream_highlights")]
+    pub stream_highlights: bool,
+    // Highlights of fewer words are skipped, e.g. a highlighted number or heading
+    #[serde(default)]
+    pub annotation_min_length: usize,
+    // Longer highlighted text is cut at the last sentence before the limit,
+    // unset for no limit
+    pub max_highlight_chars: Option<usize>,
+    // Highlights are dropped from the end of papers over these limits
+    pub max_highlights_per_paper: Option<usize>,
+    pub max_file_size_bytes: Option<u64>,
+    // Tera template of the "* ..." heading of new files, e.g. "{{ title }} ({{ year }})"
+    pub paper_heading_format: Option<String>,
+    // Number of stars of the "* zotero:highlights" heading, from 1 to 6. The
+    // headings of the highlights are one level below
+    #[serde(default = "default_highlights_heading_level")]
+    pub highlights_heading_level: u8,
+    // Drawer holding the highlights, instead of a "* zotero:highlights" heading
+    pub org_drawer_name: Option<String>,
+    // Lines written before and after the text of each highlight, e.g. "#+ATTR_ORG: :width 300"
+    pub highlight_prefix: Option<String>,
+    pub highlight_suffix: Option<String>,
+    // Line closing the highlights section, so content after it survives updates
+    pub highlights_end_marker: Option<String>,
+    // Description of attachment links instead of "Open PDF", "Open EPUB"...; empty for bare links
+    pub attachment_link_text: Option<String>,
+    // Comment written at the top of new files, e.g. to recognize generated files
+    pub file_header: Option<String>,
+    // Extra properties added to the PROPERTIES drawer of new files
+    #[serde(default)]
+    pub custom_properties: HashMap<String, String>,
+    // SQLite pragmas run on the connections to the copy of the Zotero database,
+    // e.g. cache_size = "10000"
+    #[serde(default)]
+    pub sqlite_pragma: HashMap<String, String>,
+    // Tags added to the #+FILETAGS of every new file
+    #[serde(default)]
+    pub roam_tags: Vec<String>,
+    // Creator types listed as authors, e.g. "editor" for edited volumes
+    #[serde(default = "default_creator_types")]
+    pub creator_types: Vec<String>,
+    // Drop tags starting with "_", which Zotero plugins use internally, e.g. "_tablet"
+    #[serde(default = "default_exclude_internal_tags")]
+    pub exclude_internal_tags: bool,
+    // Tags starting with one of these are dropped too
+    #[serde(default)]
+    pub tag_prefix_exclude: Vec<String>,
+    // Zotero tags renamed in org files, e.g. "machine learning" = "ml"; "" drops a tag
+    #[serde(default)]
+    pub tag_mapping: HashMap<String, String>,
+    // Turn spaces into "_" and drop the characters org doesn't allow in tags
+    #[serde(default = "default_tag_sanitize")]
+    pub tag_sanitize: bool,
+    // Only attachments of these MIME types are considered, all when empty
+    #[serde(default)]
+    pub content_type_filter: Vec<String>,
+    // Zotero items that are never synced, by item ID or by item key
+    #[serde(default)]
+    pub skip_item_ids: Vec<i64>,
+    #[serde(default)]
+    pub skip_zotero_keys: Vec<String>,
+    // Regexes matching titles of junk items, e.g. "^Sign In$" from a login page
+    #[serde(default)]
+    pub junk_title_patterns: Vec<String>,
+    // junk_title_patterns, compiled by load_settings
+    #[serde(skip)]
+    pub junk_title_regexes: Vec<Regex>,
+    // Items with shorter titles are skipped as junk
+    #[serde(default = "default_min_title_length")]
+    pub min_title_length: usize,
+    // Remove the url_strip_params query parameters, used for tracking, from URLs
+    #[serde(default)]
+    pub source_url_clean: bool,
+    #[serde(default = "default_url_strip_params")]
+    pub url_strip_params: Vec<String>,
+    // Removed from the start and end of titles, ignoring case
+    #[serde(default)]
+    pub title_prefix_strip: Vec<String>,
+    #[serde(default)]
+    pub title_suffix_strip: Vec<String>,
+    #[serde(default)]
+    pub title_case: TitleCaseMode,
+    // Namespace of the node IDs, which are derived from the paper's ref. Generated
+    // and written to config.toml on first use, so that it is unique per installation
+    pub uuid_namespace: Option<Uuid>,
+    // Create new files in subdirectories named after the paper's Zotero collection
+    #[serde(default)]
+    pub organize_by_collection: bool,
+    // Generate new file names from Zotero's short title when there is one
+    #[serde(default)]
+    pub use_short_title_for_filename: bool,
+    // Start new file names with the first author and year instead of a timestamp
+    #[serde(default)]
+    pub author_field_for_filename: bool,
+    // Separators of the `authors` string, e.g. ", " and " and "
+    #[serde(default = "default_authors_separator")]
+    pub authors_separator: String,
+    pub authors_last_separator: Option<String>,
+    #[serde(default)]
+    pub zotero_uri_scheme: ZoteroUriScheme,
+    // Name of the property holding the node's UUID
+    #[serde(default = "default_org_id_property")]
+    pub org_id_property: String,
+    // Name of the property holding the node's ref, also used to find existing files
+    #[serde(default = "default_org_roam_ref_property")]
+    pub org_roam_ref_property: String,
+    #[serde(default)]
+    pub org_roam_version: OrgRoamVersion,
+    #[serde(default)]
+    pub highlight_format: HighlightFormat,
+    #[serde(default)]
+    pub note_format: NoteFormat,
+    #[serde(default)]
+    pub link_format: LinkFormat,
+    #[serde(default)]
+    pub highlight_sort: HighlightSortOrder,
+    // Template names, relative to templates_dir. See the accessors below for defaults
+    pub new_file_template: Option<String>,
+    pub update_file_template: Option<String>,
+    pub highlights_template: Option<String>,
 }
 
-pub static SETTINGS: Lazy<Settings> = Lazy::new(|| {
+/// Where the `zotero_url` of papers points to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ZoteroUriScheme {
+    /// `zotero://select/...`, opening the item in the Zotero app
+    #[default]
+    Desktop,
+    /// zotero.org links for items of the user with the given ID and of groups
+    WebPersonal(u64),
+    /// zotero.org links for items of groups, desktop links for the personal library
+    WebGroup,
+}
+
+/// Capitalization applied to titles, after `title_prefix_strip` and `title_suffix_strip`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum TitleCaseMode {
+    /// Titles are kept as they are in Zotero
+    #[default]
+    Preserve,
+    /// Each major word is capitalized, e.g. "Attention Is All You Need"
+    TitleCase,
+    /// Only the first word is capitalized, along with words that look like
+    /// acronyms or proper nouns, e.g. "Scaling laws for BERT"
+    SentenceCase,
+}
+
+/// How the text of each highlight is wrapped in `highlights.tera`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HighlightFormat {
+    /// `#+BEGIN_QUOTE` / `#+END_QUOTE` block
+    OrgQuote,
+    /// Plain paragraph
+    #[default]
+    Paragraph,
+    /// Drawer with the given name, e.g. `:HIGHLIGHT:` ... `:END:`
+    Drawer(String),
+}
+
+impl HighlightFormat {
+    /// The name templates compare `highlight_format` against.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HighlightFormat::OrgQuote => "org_quote",
+            HighlightFormat::Paragraph => "paragraph",
+            HighlightFormat::Drawer(_) => "drawer",
+        }
+    }
+}
+
+/// How highlight comments are rendered in `highlights.tera`.
+/// Serialized as `note_format` in the template context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum NoteFormat {
+    /// Paragraph right after the highlighted text
+    Inline,
+    /// `*** note` heading below the highlight
+    #[default]
+    SubHeading,
+    /// `:NOTE:` property of the highlight heading
+    OrgProperty,
+    /// Notes are not rendered
+    Suppress,
+}
+
+/// How the `org_link_format` template filter renders links.
+/// Serialized as `link_format` in the template context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum LinkFormat {
+    /// `[[url][description]]`
+    WithDescription,
+    /// The URL alone, which org still recognizes as a link
+    #[default]
+    Bare,
+    /// `[cite:@key]` for links given a key, e.g. Zotero links
+    OrgCite,
+}
+
+/// Org-roam version new files are written for.
+/// Serialized as `org_roam_version` in the template context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum OrgRoamVersion {
+    /// `#+ROAM_KEY:`, `#+ROAM_ALIAS:` and `#+ROAM_TAGS:` keywords
+    V1,
+    /// A `PROPERTIES` drawer with an ID and `:ROAM_REFS:`
+    #[default]
+    V2,
+}
+
+/// Encoding of the org files read and written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FileEncoding {
+    #[default]
+    Utf8,
+    /// UTF-8 starting with a byte order mark
+    Utf8Bom,
+    /// org-roam requires UTF-8, only for org files used outside of org-roam
+    Latin1,
+}
+
+/// Reads `file_permissions` from an octal string, e.g. `"0o644"` or `"644"`, or
+/// from a TOML integer, e.g. `0o644`.
+fn deserialize_file_permissions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    struct FilePermissionsVisitor;
+
+    impl Visitor<'_> for FilePermissionsVisitor {
+        type Value = u32;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("Unix permissions as an octal string, e.g. \"0o644\"")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<u32, E> {
+            let digits = value.trim();
+            let digits = digits.strip_prefix("0o").unwrap_or(digits);
+            let mode = u32::from_str_radix(digits, 8)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))?;
+            self.visit_u64(mode.into())
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<u32, E> {
+            match u32::try_from(value) {
+                Ok(mode) if mode <= 0o7777 => Ok(mode),
+                _ => Err(E::invalid_value(de::Unexpected::Unsigned(value), &self)),
+            }
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<u32, E> {
+            match u64::try_from(value) {
+                Ok(value) => self.visit_u64(value),
+                Err(_) => Err(E::invalid_value(de::Unexpected::Signed(value), &self)),
+            }
+        }
+    }
+
+    deserializer
+        .deserialize_any(FilePermissionsVisitor)
+        .map(Some)
+}
+
+/// Order of the highlights of a paper, see `sort::sorter`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HighlightSortOrder {
+    /// Position in the document
+    #[default]
+    Position,
+    /// Date the annotation was added
+    Date,
+    /// Grouped by highlight color
+    Color,
+    /// Shortest first
+    Length,
+}
+
+fn default_stream_highlights() -> bool {
+    true
+}
+
+fn default_creator_types() -> Vec<String> {
+    vec!["author".to_string()]
+}
+
+fn default_url_strip_params() -> Vec<String> {
+    [
+        "utm_source",
+        "utm_medium",
+        "utm_campaign",
+        "utm_term",
+        "utm_content",
+        "ref",
+        "fbclid",
+        "gclid",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_tag_sanitize() -> bool {
+    true
+}
+
+fn default_exclude_internal_tags() -> bool {
+    true
+}
+
+fn default_highlights_heading_level() -> u8 {
+    1
+}
+
+fn default_authors_separator() -> String {
+    ", ".to_string()
+}
+
+fn default_org_id_property() -> String {
+    "ID".to_string()
+}
+
+fn default_min_title_length() -> usize {
+    3
+}
+
+fn default_org_roam_ref_property() -> String {
+    "ROAM_REFS".to_string()
+}
+
+fn config_dir() -> PathBuf {
     let home_dir = std::env::var("HOME").expect("HOME environment variable not set");
-    let config_dir = PathBuf::from(&home_dir).join(".config/org-zotero-rust");
-    let config = Config::builder()
+    PathBuf::from(&home_dir).join(".config/org-zotero-rust")
+}
+
+#[cfg(not(test))]
+pub static SETTINGS: Lazy<Settings> =
+    Lazy::new(|| load_settings().unwrap_or_else(|e| panic!("{}", e)));
+
+// Tests don't depend on the user's config.toml
+#[cfg(test)]
+pub static SETTINGS: Lazy<Settings> = Lazy::new(crate::test_helpers::test_settings);
+
+/// Reads and checks `~/.config/org-zotero-rust/config.toml`. Most code uses
+/// `SETTINGS`, which panics with the error instead.
+pub fn load_settings() -> Result<Settings, String> {
+    let home_dir = env::var("HOME").map_err(|_| "HOME environment variable not set")?;
+    let config_dir = config_dir();
+    let mut settings = Config::builder()
         .set_default("config_dir", config_dir.to_string_lossy().to_string())
-        .unwrap()
-        .add_source(File::with_name(
-            &config_dir.join("config.toml").to_string_lossy(),
-        ))
-        .build()
-        .expect("Failed to load configuration from ~/.config/org-zotero-rust/config.toml");
+        .and_then(|builder| {
+            builder
+                .add_source(File::with_name(
+                    &config_dir.join("config.toml").to_string_lossy(),
+                ))
+                .build()
+        })
+        .and_then(Config::try_deserialize::<Settings>)
+        .map_err(|e| {
+            format!(
+                "Failed to load configuration from ~/.config/org-zotero-rust/config.toml: {}",
+                e
+            )
+        })?;
 
-    let mut settings = config.try_deserialize::<Settings>().unwrap();
+    if !(1..=6).contains(&settings.highlights_heading_level) {
+        return Err(format!(
+            "highlights_heading_level must be between 1 and 6, got {}",
+            settings.highlights_heading_level
+        ));
+    }
 
-    // Expand ~ to home directory for all PathBuf fields
+    if settings.max_highlight_chars == Some(0) {
+        return Err(
+            "max_highlight_chars must be at least 1, leave it unset for no limit".to_string(),
+        );
+    }
+
+    settings.junk_title_regexes = settings
+        .junk_title_patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|e| format!("Invalid junk_title_patterns entry {:?}: {}", pattern, e))
+        })
+        .collect::<Result<_, _>>()?;
+
+    if settings.zotero_db_path.as_os_str().is_empty() && settings.zotero_local_api_port.is_none() {
+        settings.zotero_db_path = Settings::detect_zotero_profile().ok_or(
+            "zotero_db_path is not set and no Zotero database could be detected, \
+             please set it in ~/.config/org-zotero-rust/config.toml",
+        )?;
+    }
+
+    // Expand ~, environment variables and relative paths for all PathBuf fields
     for path in [
         &mut settings.org_roam_dir,
         &mut settings.templates_dir,
         &mut settings.zotero_db_path,
-    ] {
-        if path.starts_with("~") {
-            *path = PathBuf::from(&home_dir).join(path.strip_prefix("~").unwrap());
+    ]
+    .into_iter()
+    .chain(settings.zotero_storage_dir.as_mut())
+    .chain(settings.org_roam_db_path.as_mut())
+    .chain(settings.summary_output.as_mut())
+    .chain(settings.dry_run_output_dir.as_mut())
+    {
+        *path = expand_path(path.clone(), &home_dir, &config_dir);
+    }
+    Ok(settings)
+}
+
+/// Namespace of the deterministic node IDs, see `Settings::uuid_namespace`.
+/// Without one in the config, a namespace is generated for the run, and only
+/// kept if `save_uuid_namespace` writes it to the config file.
+pub static UUID_NAMESPACE: Lazy<Uuid> =
+    Lazy::new(|| SETTINGS.uuid_namespace.unwrap_or_else(Uuid::new_v4));
+
+/// Adds the generated `UUID_NAMESPACE` to the config file when it sets none,
+/// returning the path of the config file written.
+pub fn save_uuid_namespace() -> io::Result<Option<PathBuf>> {
+    if SETTINGS.uuid_namespace.is_some() {
+        return Ok(None);
+    }
+    let config_path = config_dir().join("config.toml");
+    write_uuid_namespace(&config_path, *UUID_NAMESPACE)?;
+    Ok(Some(config_path))
+}
+
+/// Adds `uuid_namespace` to the config file, before its first table so that
+/// the key stays at the top level.
+fn write_uuid_namespace(config_path: &Path, namespace: Uuid) -> io::Result<()> {
+    let content = fs::read_to_string(config_path)?;
+    let line = format!("uuid_namespace = \"{}\"\n", namespace);
+    let first_table = std::iter::once(0)
+        .chain(content.match_indices('\n').map(|(index, _)| index + 1))
+        .find(|&start| content[start..].trim_start().starts_with('['));
+    let content = match first_table {
+        Some(start) => format!("{}{}{}", &content[..start], line, &content[start..]),
+        None if content.is_empty() || content.ends_with('\n') => format!("{}{}", content, line),
+        None => format!("{}\n{}", content, line),
+    };
+    fs::write(config_path, content)
+}
+
+impl Settings {
+    /// Stars of the highlights heading, e.g. `**` at level 2.
+    pub fn highlights_heading_stars(&self) -> String {
+        "*".repeat(self.highlights_heading_level as usize)
+    }
+
+    /// Heading of the highlights section, found again when files are updated.
+    pub fn highlights_heading(&self) -> String {
+        format!("{} zotero:highlights", self.highlights_heading_stars())
+    }
+
+    /// Template rendering the whole document of a new file.
+    pub fn new_file_template(&self) -> &str {
+        self.new_file_template
+            .as_deref()
+            .unwrap_or("document.org.tera")
+    }
+
+    /// Template rendering the highlights section of a new file.
+    pub fn highlights_template(&self) -> &str {
+        self.highlights_template
+            .as_deref()
+            .unwrap_or("highlights.tera")
+    }
+
+    /// Template rendering the highlights section when an existing file is updated.
+    /// Only that section is rewritten, so this is a highlights template too.
+    pub fn update_file_template(&self) -> &str {
+        self.update_file_template
+            .as_deref()
+            .unwrap_or_else(|| self.highlights_template())
+    }
+
+    /// Finds the `zotero.sqlite` of the default Zotero profile, by reading
+    /// `profiles.ini` and the data directory configured in the profile's `prefs.js`.
+    pub fn detect_zotero_profile() -> Option<PathBuf> {
+        let home_dir = PathBuf::from(env::var("HOME").ok()?);
+        let profiles_ini = [
+            home_dir.join(".zotero/zotero/profiles.ini"),
+            home_dir.join("Library/Application Support/Zotero/profiles.ini"),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())?;
+        let profile_dir = default_profile_dir(&profiles_ini)?;
+
+        let mut candidates = Vec::new();
+        if let Some(data_dir) = prefs_data_dir(&profile_dir.join("prefs.js")) {
+            candidates.push(data_dir.join("zotero.sqlite"));
         }
-        if path.is_relative() {
-            *path = config_dir.join(path.clone());
+        // Old Zotero versions kept the database inside the profile directory
+        candidates.push(profile_dir.join("zotero/zotero.sqlite"));
+        candidates.push(home_dir.join("Zotero/zotero.sqlite"));
+        candidates.into_iter().find(|path| path.is_file())
+    }
+}
+
+/// Returns the directory of the profile marked `Default=1` in `profiles.ini`,
+/// or of the only profile if there is just one.
+fn default_profile_dir(profiles_ini: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(profiles_ini).ok()?;
+
+    let mut profiles: Vec<HashMap<String, String>> = Vec::new();
+    let mut in_profile_section = false;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            // Other sections, e.g. [General], are ignored
+            in_profile_section = line.starts_with("[Profile");
+            if in_profile_section {
+                profiles.push(HashMap::new());
+            }
+        } else if let (true, Some((key, value))) = (in_profile_section, line.split_once('=')) {
+            if let Some(profile) = profiles.last_mut() {
+                profile.insert(key.trim().to_string(), value.trim().to_string());
+            }
         }
     }
-    settings
-});
+    profiles.retain(|profile| profile.contains_key("Path"));
+
+    let profile = match profiles
+        .iter()
+        .find(|profile| profile.get("Default").map(String::as_str) == Some("1"))
+    {
+        Some(profile) => profile,
+        None if profiles.len() == 1 => &profiles[0],
+        None => return None,
+    };
+    let path = PathBuf::from(&profile["Path"]);
+    if profile.get("IsRelative").map(String::as_str) == Some("1") {
+        Some(profiles_ini.parent()?.join(path))
+    } else {
+        Some(path)
+    }
+}
+
+/// Reads `extensions.zotero.dataDir` from a profile's `prefs.js`.
+fn prefs_data_dir(prefs_js: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(prefs_js).ok()?;
+    content.lines().find_map(|line| {
+        let rest = line
+            .trim()
+            .strip_prefix(r#"user_pref("extensions.zotero.dataDir","#)?;
+        let value = rest.trim().strip_suffix(");")?.trim();
+        let value = value.strip_prefix('"')?.strip_suffix('"')?;
+        Some(PathBuf::from(value.replace(r"\\", r"\")))
+    })
+}
+
+/// Expands a leading `~` and `$VAR` / `${VAR}` references, then resolves
+/// relative paths against the config directory.
+pub fn expand_path(path: PathBuf, home_dir: &str, config_dir: &Path) -> PathBuf {
+    let expanded = PathBuf::from(expand_env_vars(&path.to_string_lossy(), home_dir));
+    let expanded = match expanded.strip_prefix("~") {
+        Ok(rest) => PathBuf::from(home_dir).join(rest),
+        Err(_) => expanded,
+    };
+    if expanded.is_relative() {
+        config_dir.join(expanded)
+    } else {
+        expanded
+    }
+}
+
+fn env_var_value(name: &str, home_dir: &str) -> Option<String> {
+    match env::var(name) {
+        Ok(value) if !value.is_empty() => Some(value),
+        _ => match name {
+            "HOME" => Some(home_dir.to_string()),
+            // Default from the XDG base directory specification
+            "XDG_DATA_HOME" => Some(format!("{}/.local/share", home_dir)),
+            _ => None,
+        },
+    }
+}
+
+/// Replaces `$VAR` and `${VAR}` with the value of the environment variable.
+/// References to unset variables are left as they are.
+fn expand_env_vars(input: &str, home_dir: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, reference_len) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 3),
+                None => ("", 1),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end + 1)
+        };
+        match env_var_value(name, home_dir).filter(|_| !name.is_empty()) {
+            Some(value) => output.push_str(&value),
+            None => output.push_str(&rest[start..start + reference_len]),
+        }
+        rest = &rest[start + reference_len..];
+    }
+    output.push_str(rest);
+    output
+}
@@ -0,0 +1,55 @@
+use crate::settings::HighlightSortOrder;
+use crate::HighlightJson;
+
+/// Orders the highlights of one paper before they are rendered.
+///
+/// Sorts are stable, and highlights arrive in document order, so ties keep
+/// their position in the document.
+pub trait HighlightSorter: Send + Sync {
+    fn sort(&self, highlights: &mut [HighlightJson]);
+}
+
+pub struct ByPosition;
+pub struct ByDate;
+pub struct ByColor;
+pub struct ByLength;
+
+impl HighlightSorter for ByPosition {
+    fn sort(&self, highlights: &mut [HighlightJson]) {
+        highlights.sort_by(|a, b| a.sort_index.cmp(&b.sort_index));
+    }
+}
+
+impl HighlightSorter for ByDate {
+    fn sort(&self, highlights: &mut [HighlightJson]) {
+        highlights.sort_by(|a, b| a.date_added.cmp(&b.date_added));
+    }
+}
+
+impl HighlightSorter for ByColor {
+    fn sort(&self, highlights: &mut [HighlightJson]) {
+        highlights.sort_by(|a, b| a.color.cmp(&b.color));
+    }
+}
+
+impl HighlightSorter for ByLength {
+    fn sort(&self, highlights: &mut [HighlightJson]) {
+        // Standalone notes have no content, their length is the note's
+        highlights.sort_by_key(|highlight| {
+            if highlight.content.is_empty() {
+                highlight.note.chars().count()
+            } else {
+                highlight.content.chars().count()
+            }
+        });
+    }
+}
+
+pub fn sorter(order: HighlightSortOrder) -> Box<dyn HighlightSorter> {
+    match order {
+        HighlightSortOrder::Position => Box::new(ByPosition),
+        HighlightSortOrder::Date => Box::new(ByDate),
+        HighlightSortOrder::Color => Box::new(ByColor),
+        HighlightSortOrder::Length => Box::new(ByLength),
+    }
+}
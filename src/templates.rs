@@ -0,0 +1,209 @@
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tera::{Context, Tera, Value};
+
+/// Registers the filters of org-zotero-rust on `tera`, which every instance
+/// rendering templates needs.
+pub fn register_filters(tera: &mut Tera) {
+    tera.register_filter("org_link_format", org_link_format);
+}
+
+/// `{{ zotero_url | org_link_format(desc="Open in Zotero", format=link_format, key=item_key) }}`
+/// renders a link in the given `link_format`, `with_description` by default.
+/// `key` is only used by `org_cite`, which renders other links with their description.
+fn org_link_format(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let url = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("org_link_format can only format strings"))?;
+    let arg = |name: &str| args.get(name).and_then(Value::as_str);
+    Ok(Value::String(format_org_link(
+        url,
+        arg("desc").unwrap_or_default(),
+        arg("format").unwrap_or("with_description"),
+        arg("key"),
+    )))
+}
+
+fn format_org_link(url: &str, desc: &str, format: &str, key: Option<&str>) -> String {
+    // Brackets would end the description early
+    let desc = desc.replace('[', "(").replace(']', ")");
+    match (format, key) {
+        ("bare", _) => url.to_string(),
+        ("org_cite", Some(key)) if !key.is_empty() => format!("[cite:@{}]", key),
+        _ if desc.is_empty() => format!("[[{}]]", url),
+        _ => format!("[[{}][{}]]", url, desc),
+    }
+}
+
+/// Returns the directory part of a templates glob such as `templates/**/*`.
+pub fn glob_base_dir(templates_glob: &Path) -> PathBuf {
+    templates_glob
+        .components()
+        .take_while(|component| {
+            let part = component.as_os_str().to_string_lossy();
+            !part.contains(['*', '?', '[', '{'])
+        })
+        .collect()
+}
+
+/// Lists the `.tera` files under `dir`, recursively, sorted by path.
+pub fn template_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(template_files(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "tera") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Formats an error together with all of its sources, one per line.
+pub fn format_error_chain(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!("\n    caused by: {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Prints a table of the templates found for `templates_glob`, with their
+/// roles from `in_use` (pairs of a role such as "new files" and a template
+/// name), modification time and path. Templates that fail to parse are flagged
+/// with the error. Never fails, this is only informational.
+pub fn list_templates(templates_glob: &Path, in_use: &[(&str, &str)]) {
+    let base_dir = glob_base_dir(templates_glob);
+    let files = template_files(&base_dir).unwrap_or_else(|e| {
+        println!("Cannot read {}: {}", base_dir.display(), e);
+        Vec::new()
+    });
+
+    let mut rows: Vec<[String; 4]> = Vec::new();
+    for path in &files {
+        let name = path
+            .strip_prefix(&base_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned();
+        let roles: Vec<&str> = in_use
+            .iter()
+            .filter(|(_, template)| *template == name)
+            .map(|(role, _)| *role)
+            .collect();
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map(|time| {
+                DateTime::<Local>::from(time)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| "?".to_string());
+        let mut source = path.display().to_string();
+        if let Err(e) = Tera::default().add_template_file(path, Some(&name)) {
+            source = format!(
+                "{} [SYNTAX ERROR] {}",
+                source,
+                format_error_chain(&e).replace('\n', " ")
+            );
+        }
+        rows.push([name, roles.join(", "), modified, source]);
+    }
+    for (role, template) in in_use {
+        if !rows.iter().any(|row| row[0] == *template) {
+            rows.push([
+                template.to_string(),
+                role.to_string(),
+                "-".to_string(),
+                "[MISSING]".to_string(),
+            ]);
+        }
+    }
+
+    let header = ["TEMPLATE", "USED FOR", "MODIFIED", "SOURCE"].map(str::to_string);
+    let widths: Vec<usize> = (0..3)
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in std::iter::once(&header).chain(&rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+    }
+}
+
+/// Prints each of `in_use` (pairs of a role and a template name) that `tera`
+/// didn't load, with the path it was expected at. Returns whether all were found.
+pub fn report_missing_templates(
+    tera: &Tera,
+    templates_glob: &Path,
+    in_use: &[(&str, &str)],
+) -> bool {
+    let base_dir = glob_base_dir(templates_glob);
+    let mut all_found = true;
+    for (role, name) in in_use {
+        if tera.get_template(name).is_err() {
+            all_found = false;
+            eprintln!(
+                "Template {:?}, used for {}, is missing: expected it at {}",
+                name,
+                role,
+                base_dir.join(name).display()
+            );
+        }
+    }
+    all_found
+}
+
+/// Parses each template on its own and renders it with `context`, printing
+/// "OK" or the error for every file. Returns whether all templates passed.
+pub fn check_templates(templates_glob: &Path, context: &Context) -> io::Result<bool> {
+    let base_dir = glob_base_dir(templates_glob);
+    let files = template_files(&base_dir)?;
+    if files.is_empty() {
+        println!("No .tera templates found in {}", base_dir.display());
+        return Ok(false);
+    }
+
+    let mut all_ok = true;
+    for path in files {
+        let name = path
+            .strip_prefix(&base_dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        let mut tera = Tera::default();
+        register_filters(&mut tera);
+        let result = tera
+            .add_template_file(&path, Some(&name))
+            .and_then(|_| tera.render(&name, context));
+        match result {
+            Ok(_) => println!("{}: OK", name),
+            Err(e) => {
+                all_ok = false;
+                println!("{}: ERROR\n    {}", name, format_error_chain(&e));
+            }
+        }
+    }
+    Ok(all_ok)
+}
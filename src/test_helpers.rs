@@ -0,0 +1,378 @@
+// Helpers for tests that need a Zotero database; not every test uses every helper.
+#![allow(dead_code)]
+
+use crate::settings::Settings;
+use config::{Config, File, FileFormat};
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// The config.toml of `test_settings`, which tests see as `SETTINGS`. Paths
+/// point to nothing on purpose: attachments are never found on disk.
+const TEST_CONFIG: &str = r#"
+org_roam_dir = "/nonexistent/org-zotero-rust-test/roam"
+templates_dir = "templates/**/*"
+zotero_db_path = "/nonexistent/org-zotero-rust-test/zotero.sqlite"
+uuid_namespace = "6ba7b810-9dad-11d1-80b4-00c04fd430c8"
+"#;
+
+/// Settings with the defaults of every optional setting, for tests that
+/// change some of them before passing them to the code under test.
+pub fn test_settings() -> Settings {
+    Config::builder()
+        .add_source(File::from_str(TEST_CONFIG, FileFormat::Toml))
+        .build()
+        .and_then(Config::try_deserialize)
+        .expect("test settings")
+}
+
+/// The tables and columns of the Zotero schema that the queries read.
+const TEST_SCHEMA: &str = r#"
+CREATE TABLE items (itemID INTEGER PRIMARY KEY, itemTypeID INT, dateAdded TEXT DEFAULT CURRENT_TIMESTAMP, dateModified TEXT DEFAULT CURRENT_TIMESTAMP, libraryID INT DEFAULT 1, key TEXT);
+CREATE TABLE itemTypes (itemTypeID INTEGER PRIMARY KEY, typeName TEXT);
+CREATE TABLE itemData (itemID INT, fieldID INT, valueID INT, PRIMARY KEY (itemID, fieldID));
+CREATE TABLE itemDataValues (valueID INTEGER PRIMARY KEY, value);
+CREATE TABLE creators (creatorID INTEGER PRIMARY KEY, firstName TEXT, lastName TEXT, fieldMode INT);
+CREATE TABLE creatorTypes (creatorTypeID INTEGER PRIMARY KEY, creatorType TEXT);
+CREATE TABLE itemCreators (itemID INT, creatorID INT, creatorTypeID INT, orderIndex INT);
+CREATE TABLE itemAttachments (itemID INTEGER PRIMARY KEY, parentItemID INT, linkMode INT, contentType TEXT, path TEXT);
+CREATE TABLE itemAnnotations (itemID INTEGER PRIMARY KEY, parentItemID INT, type INT, text TEXT, comment TEXT, color TEXT, sortIndex TEXT);
+CREATE TABLE itemNotes (itemID INTEGER PRIMARY KEY, parentItemID INT, note TEXT, title TEXT);
+CREATE TABLE tags (tagID INTEGER PRIMARY KEY, name TEXT UNIQUE);
+CREATE TABLE itemTags (itemID INT, tagID INT, type INT DEFAULT 0);
+CREATE TABLE collections (collectionID INTEGER PRIMARY KEY, collectionName TEXT, parentCollectionID INT, libraryID INT DEFAULT 1, key TEXT);
+CREATE TABLE collectionItems (collectionID INT, itemID INT, orderIndex INT DEFAULT 0);
+CREATE TABLE deletedItems (itemID INTEGER PRIMARY KEY, dateDeleted TEXT DEFAULT CURRENT_TIMESTAMP);
+CREATE TABLE groups (groupID INTEGER PRIMARY KEY, libraryID INT, name TEXT);
+CREATE TABLE itemRelations (itemID INT, predicateID INT, object TEXT);
+CREATE TABLE relationPredicates (predicateID INTEGER PRIMARY KEY, predicate TEXT);
+INSERT INTO itemTypes (typeName) VALUES ('journalArticle'), ('book'), ('attachment'), ('annotation'), ('note');
+INSERT INTO creatorTypes (creatorType) VALUES ('author'), ('editor'), ('translator');
+"#;
+
+/// Field IDs of `itemData`, as used by `query_papers`.
+const TITLE_FIELD: i64 = 1;
+const DATE_FIELD: i64 = 6;
+const URL_FIELD: i64 = 13;
+
+/// A paper to insert with `create_test_db`, built like `PaperBuilder`.
+#[derive(Debug, Clone)]
+pub struct TestPaper {
+    key: Option<String>,
+    item_type: String,
+    title: String,
+    url: Option<String>,
+    date: Option<String>,
+    date_added: String,
+    // (first name, last name, creator type)
+    creators: Vec<(String, String, String)>,
+    tags: Vec<String>,
+    collections: Vec<String>,
+    // (path, content type, trashed), annotations are on the first one
+    attachments: Vec<(String, String, bool)>,
+    // (HTML, trashed)
+    notes: Vec<(String, bool)>,
+    // (text, comment)
+    annotations: Vec<(Option<String>, Option<String>)>,
+    trashed: bool,
+}
+
+impl TestPaper {
+    pub fn new(title: &str) -> Self {
+        TestPaper {
+            key: None,
+            item_type: "journalArticle".to_string(),
+            title: title.to_string(),
+            url: None,
+            date: None,
+            date_added: "2024-01-01 00:00:00".to_string(),
+            creators: Vec::new(),
+            tags: Vec::new(),
+            collections: Vec::new(),
+            attachments: vec![(
+                "storage:paper.pdf".to_string(),
+                "application/pdf".to_string(),
+                false,
+            )],
+            notes: Vec::new(),
+            annotations: Vec::new(),
+            trashed: false,
+        }
+    }
+
+    /// Sets the item key, generated from the item ID when not set.
+    pub fn key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    /// Sets the item type, one of `journalArticle` (default) or `book`.
+    pub fn item_type(mut self, item_type: &str) -> Self {
+        self.item_type = item_type.to_string();
+        self
+    }
+
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(url.to_string());
+        self
+    }
+
+    /// Sets the publication date, e.g. `2021-05-03`.
+    pub fn date(mut self, date: &str) -> Self {
+        self.date = Some(date.to_string());
+        self
+    }
+
+    /// Sets when the paper was added, e.g. `2024-01-01 00:00:00`.
+    pub fn date_added(mut self, date_added: &str) -> Self {
+        self.date_added = date_added.to_string();
+        self
+    }
+
+    pub fn author(self, first_name: &str, last_name: &str) -> Self {
+        self.creator(first_name, last_name, "author")
+    }
+
+    /// Appends a creator of the given type: `author`, `editor` or `translator`.
+    pub fn creator(mut self, first_name: &str, last_name: &str, creator_type: &str) -> Self {
+        self.creators.push((
+            first_name.to_string(),
+            last_name.to_string(),
+            creator_type.to_string(),
+        ));
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Adds the paper to a top-level collection, created on first use.
+    pub fn collection(mut self, name: &str) -> Self {
+        self.collections.push(name.to_string());
+        self
+    }
+
+    /// Replaces the default `storage:paper.pdf` attachment.
+    pub fn attachment(mut self, path: &str, content_type: &str) -> Self {
+        self.attachments = vec![(path.to_string(), content_type.to_string(), false)];
+        self
+    }
+
+    /// Adds an attachment after the existing ones.
+    pub fn extra_attachment(mut self, path: &str, content_type: &str) -> Self {
+        self.attachments
+            .push((path.to_string(), content_type.to_string(), false));
+        self
+    }
+
+    /// Adds an attachment in Zotero's trash after the existing ones.
+    pub fn trashed_attachment(mut self, path: &str, content_type: &str) -> Self {
+        self.attachments
+            .push((path.to_string(), content_type.to_string(), true));
+        self
+    }
+
+    /// Appends a child note with the given HTML.
+    pub fn note(mut self, html: &str) -> Self {
+        self.notes.push((html.to_string(), false));
+        self
+    }
+
+    /// Appends a child note in Zotero's trash.
+    pub fn trashed_note(mut self, html: &str) -> Self {
+        self.notes.push((html.to_string(), true));
+        self
+    }
+
+    /// Removes the attachments, so that `query_papers` skips the paper.
+    pub fn no_attachment(mut self) -> Self {
+        self.attachments.clear();
+        self
+    }
+
+    /// Moves the paper to Zotero's trash, so that `query_papers` skips it.
+    pub fn trashed(mut self) -> Self {
+        self.trashed = true;
+        self
+    }
+
+    /// Appends an annotation on the attachment: a highlight, or a sticky
+    /// note when `text` is `None`.
+    pub fn annotation(mut self, text: Option<&str>, comment: Option<&str>) -> Self {
+        self.annotations
+            .push((text.map(str::to_string), comment.map(str::to_string)));
+        self
+    }
+}
+
+/// Opens an in-memory database with the Zotero schema and inserts `papers`,
+/// their attachments, notes and annotations, in order.
+pub fn create_test_db(papers: &[TestPaper]) -> Connection {
+    let conn = Connection::open_in_memory().expect("in-memory database");
+    conn.execute_batch(TEST_SCHEMA).expect("test schema");
+    for paper in papers {
+        insert_paper(&conn, paper);
+    }
+    conn
+}
+
+fn insert_paper(conn: &Connection, paper: &TestPaper) {
+    let paper_id = insert_item(
+        conn,
+        &paper.item_type,
+        paper.key.as_deref(),
+        &paper.date_added,
+    );
+    insert_field(conn, paper_id, TITLE_FIELD, &paper.title);
+    if let Some(url) = &paper.url {
+        insert_field(conn, paper_id, URL_FIELD, url);
+    }
+    if let Some(date) = &paper.date {
+        insert_field(conn, paper_id, DATE_FIELD, date);
+    }
+    if paper.trashed {
+        trash_item(conn, paper_id);
+    }
+
+    for (order_index, (first_name, last_name, creator_type)) in paper.creators.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO creators (firstName, lastName, fieldMode) VALUES (?1, ?2, 0)",
+            params![first_name, last_name],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO itemCreators (itemID, creatorID, creatorTypeID, orderIndex)
+             SELECT ?1, ?2, creatorTypeID, ?3 FROM creatorTypes WHERE creatorType = ?4",
+            params![
+                paper_id,
+                conn.last_insert_rowid(),
+                order_index as i64,
+                creator_type
+            ],
+        )
+        .unwrap();
+    }
+
+    for tag in &paper.tags {
+        conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", [tag])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO itemTags (itemID, tagID) SELECT ?1, tagID FROM tags WHERE name = ?2",
+            params![paper_id, tag],
+        )
+        .unwrap();
+    }
+
+    for collection in &paper.collections {
+        let existing: Option<i64> = conn
+            .query_row(
+                "SELECT collectionID FROM collections WHERE collectionName = ?1",
+                [collection],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        let collection_id = match existing {
+            Some(collection_id) => collection_id,
+            None => {
+                conn.execute(
+                    "INSERT INTO collections (collectionName) VALUES (?1)",
+                    [collection],
+                )
+                .unwrap();
+                conn.last_insert_rowid()
+            }
+        };
+        conn.execute(
+            "INSERT INTO collectionItems (collectionID, itemID) VALUES (?1, ?2)",
+            params![collection_id, paper_id],
+        )
+        .unwrap();
+    }
+
+    let attachment_ids: Vec<i64> = paper
+        .attachments
+        .iter()
+        .map(|(path, content_type, trashed)| {
+            let attachment_id = insert_item(conn, "attachment", None, &paper.date_added);
+            conn.execute(
+                "INSERT INTO itemAttachments (itemID, parentItemID, linkMode, contentType, path)
+                 VALUES (?1, ?2, 0, ?3, ?4)",
+                params![attachment_id, paper_id, content_type, path],
+            )
+            .unwrap();
+            if *trashed {
+                trash_item(conn, attachment_id);
+            }
+            attachment_id
+        })
+        .collect();
+
+    for (html, trashed) in &paper.notes {
+        let note_id = insert_item(conn, "note", None, &paper.date_added);
+        conn.execute(
+            "INSERT INTO itemNotes (itemID, parentItemID, note, title) VALUES (?1, ?2, ?3, '')",
+            params![note_id, paper_id, html],
+        )
+        .unwrap();
+        if *trashed {
+            trash_item(conn, note_id);
+        }
+    }
+
+    let Some(&attachment_id) = attachment_ids.first() else {
+        return;
+    };
+
+    for (index, (text, comment)) in paper.annotations.iter().enumerate() {
+        let annotation_id = insert_item(conn, "annotation", None, &paper.date_added);
+        conn.execute(
+            "INSERT INTO itemAnnotations (itemID, parentItemID, type, text, comment, color, sortIndex)
+             VALUES (?1, ?2, ?3, ?4, ?5, '#ffd400', ?6)",
+            params![
+                annotation_id,
+                attachment_id,
+                // 1 is a highlight and 2 a note in Zotero
+                if text.is_some() { 1 } else { 2 },
+                text,
+                comment,
+                format!("00000|{:06}|00000", index)
+            ],
+        )
+        .unwrap();
+    }
+}
+
+/// Inserts an item of the given type, with a key generated from its ID when
+/// `key` is not set, and returns its ID.
+fn insert_item(conn: &Connection, item_type: &str, key: Option<&str>, date_added: &str) -> i64 {
+    conn.execute(
+        "INSERT INTO items (itemTypeID, dateAdded, dateModified)
+         SELECT itemTypeID, ?2, ?2 FROM itemTypes WHERE typeName = ?1",
+        params![item_type, date_added],
+    )
+    .unwrap();
+    let item_id = conn.last_insert_rowid();
+    let key = key.map_or_else(|| format!("TEST{:04}", item_id), str::to_string);
+    conn.execute(
+        "UPDATE items SET key = ?1 WHERE itemID = ?2",
+        params![key, item_id],
+    )
+    .unwrap();
+    item_id
+}
+
+fn trash_item(conn: &Connection, item_id: i64) {
+    conn.execute("INSERT INTO deletedItems (itemID) VALUES (?1)", [item_id])
+        .unwrap();
+}
+
+fn insert_field(conn: &Connection, item_id: i64, field_id: i64, value: &str) {
+    conn.execute("INSERT INTO itemDataValues (value) VALUES (?1)", [value])
+        .unwrap();
+    conn.execute(
+        "INSERT INTO itemData (itemID, fieldID, valueID) VALUES (?1, ?2, ?3)",
+        params![item_id, field_id, conn.last_insert_rowid()],
+    )
+    .unwrap();
+}